@@ -0,0 +1,4 @@
+#[path = "../../src/avr_async/block_on.rs"]
+mod block_on;
+
+pub use block_on::block_on;
@@ -0,0 +1,15 @@
+// Unit tests for the parts of the firmware that don't touch the hardware, run on the host.  The
+// firmware itself only builds for the AVR, so rather than depending on it, this crate pulls the
+// hardware-independent source files in directly.  They're mounted at the same module paths as in
+// the firmware so that their `crate::` imports resolve the same way; the tests live next to the
+// code they cover, in `#[cfg(test)]` modules.
+#![allow(dead_code)]
+#![allow(unused_imports)]
+#![allow(clippy::needless_range_loop)] // the firmware indexes its fixed-size arrays directly
+
+mod avr_async;
+#[path = "../../src/math.rs"]
+mod math;
+#[path = "../../src/sample_ring.rs"]
+mod sample_ring;
+mod uno;
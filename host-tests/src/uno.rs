@@ -0,0 +1,14 @@
+#[path = "../../src/uno/bump_detector.rs"]
+pub mod bump_detector;
+#[path = "../../src/uno/edge.rs"]
+pub mod edge;
+#[path = "../../src/uno/eeprom_value.rs"]
+pub mod eeprom_value;
+#[path = "../../src/uno/kv_store.rs"]
+pub mod kv_store;
+#[path = "../../src/uno/line_tracker.rs"]
+pub mod line_tracker;
+#[path = "../../src/uno/mag_calibration.rs"]
+pub mod mag_calibration;
+#[path = "../../src/uno/tap_detector.rs"]
+pub mod tap_detector;
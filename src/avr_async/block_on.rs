@@ -0,0 +1,32 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+        RawWaker,
+        RawWakerVTable,
+        Waker,
+    },
+};
+
+// Run a future to completion by polling it in a loop.  This is only meant for setup code that runs
+// before the executor starts (e.g., configuring devices on the I2C bus); the futures involved have
+// to make progress just from being polled, since nobody is listening to their wakers.
+pub fn block_on<F: Future>(mut future: F) -> F::Output {
+    let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &NOOP_VTABLE)) };
+    let mut ctx = Context::from_waker(&waker);
+    loop {
+        // The future lives on our stack and never moves until we return
+        if let Poll::Ready(output) = unsafe { Pin::new_unchecked(&mut future) }.poll(&mut ctx) {
+            return output;
+        }
+    }
+}
+
+static NOOP_VTABLE: RawWakerVTable = RawWakerVTable::new(noop_clone, noop, noop, noop);
+
+unsafe fn noop_clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &NOOP_VTABLE)
+}
+unsafe fn noop(_: *const ()) {}
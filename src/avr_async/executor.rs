@@ -24,7 +24,9 @@ use core::{
 };
 use micromath::F32Ext;
 
-pub const NTASKS: usize = 8;
+// There are 12 drivers so far; the spare slots cost about 15 bytes of SRAM each (here and in the
+// per-task waker slots of the TWI and EEPROM drivers)
+pub const NTASKS: usize = 14;
 static mut EXECUTOR: Executor = Executor {
     drivers: [
         MaybeUninit::uninit(),
@@ -35,6 +37,12 @@ static mut EXECUTOR: Executor = Executor {
         MaybeUninit::uninit(),
         MaybeUninit::uninit(),
         MaybeUninit::uninit(),
+        MaybeUninit::uninit(),
+        MaybeUninit::uninit(),
+        MaybeUninit::uninit(),
+        MaybeUninit::uninit(),
+        MaybeUninit::uninit(),
+        MaybeUninit::uninit(),
     ],
     drivers_len: 0,
     work_queue: [false; NTASKS],
@@ -52,6 +60,7 @@ impl Executor {
     }
 
     pub fn add_async_driver(&mut self, future: &'static mut dyn Future<Output = !>) {
+        assert!(self.drivers_len < NTASKS, "too many drivers; raise NTASKS");
         unsafe {
             self.drivers[self.drivers_len].as_mut_ptr().write(Driver {
                 id: self.drivers_len,
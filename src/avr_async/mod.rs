@@ -1,7 +1,17 @@
+mod block_on;
 mod driver;
 mod executor;
+mod select;
 mod waiter;
 
+pub use block_on::block_on;
 pub use driver::Driver;
-pub use executor::Executor;
+pub use executor::{
+    Executor,
+    NTASKS,
+};
+pub use select::{
+    select,
+    Either,
+};
 pub use waiter::Waiter;
@@ -0,0 +1,40 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+// Resolves with whichever future finishes first; the other one is dropped.  If both are ready on
+// the same poll, the left one wins.
+pub struct Select<A: Future, B: Future> {
+    left: A,
+    right: B,
+}
+
+pub fn select<A: Future, B: Future>(left: A, right: B) -> Select<A, B> {
+    Select { left, right }
+}
+
+impl<A: Future, B: Future> Future for Select<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        // We never move either future out of self, so it's safe to pin them
+        let this = unsafe { self.get_unchecked_mut() };
+        if let Poll::Ready(output) = unsafe { Pin::new_unchecked(&mut this.left) }.poll(ctx) {
+            return Poll::Ready(Either::Left(output));
+        }
+        if let Poll::Ready(output) = unsafe { Pin::new_unchecked(&mut this.right) }.poll(ctx) {
+            return Poll::Ready(Either::Right(output));
+        }
+        Poll::Pending
+    }
+}
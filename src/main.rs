@@ -8,11 +8,15 @@
 #![feature(fmt_as_str)] // Convert panic message args to a string
 #![feature(maybe_uninit_ref)] // Get a mutable reference to a maybe-uninit driver
 #![feature(const_ptr_offset)] // Get a pointer to the MEMORY (fake heap)
+#![feature(min_const_generics)] // Store arrays of any length in the EEPROM
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
 mod avr_async;
+mod math;
 mod mem;
+mod params;
+mod sample_ring;
 mod state_machine;
 mod uno;
 mod util;
@@ -41,6 +45,7 @@ use core::cell::RefCell;
 
 #[arduino_uno::entry]
 fn main() -> ! {
+    mem::check_stack_reserve();
     let mut executor = Executor::get();
     let uno = Uno::init(&mut executor);
 
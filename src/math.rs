@@ -0,0 +1,61 @@
+// Small helpers for working with 3-vectors, which we pass around as plain (x, y, z) tuples to
+// match the sensor APIs
+use micromath::F32Ext;
+
+pub type Vector3 = (f32, f32, f32);
+
+pub fn add(a: Vector3, b: Vector3) -> Vector3 {
+    (a.0 + b.0, a.1 + b.1, a.2 + b.2)
+}
+
+pub fn sub(a: Vector3, b: Vector3) -> Vector3 {
+    (a.0 - b.0, a.1 - b.1, a.2 - b.2)
+}
+
+pub fn scale(a: Vector3, s: f32) -> Vector3 {
+    (a.0 * s, a.1 * s, a.2 * s)
+}
+
+pub fn dot(a: Vector3, b: Vector3) -> f32 {
+    a.0 * b.0 + a.1 * b.1 + a.2 * b.2
+}
+
+pub fn cross(a: Vector3, b: Vector3) -> Vector3 {
+    (a.1 * b.2 - a.2 * b.1, a.2 * b.0 - a.0 * b.2, a.0 * b.1 - a.1 * b.0)
+}
+
+pub fn norm(a: Vector3) -> f32 {
+    dot(a, a).sqrt()
+}
+
+// Returns None for (nearly) zero-length vectors, which have no direction
+pub fn normalize(a: Vector3) -> Option<Vector3> {
+    let n = norm(a);
+    if n < 1e-6 {
+        None
+    } else {
+        Some(scale(a, 1.0 / n))
+    }
+}
+
+// Wrap an angle that's at most one turn out of range back into [0, 360)
+pub fn wrap_degrees(angle: f32) -> f32 {
+    if angle >= 360.0 {
+        angle - 360.0
+    } else if angle < 0.0 {
+        angle + 360.0
+    } else {
+        angle
+    }
+}
+
+// The signed difference between two angles, in (-180, 180]
+pub fn degrees_delta(from: f32, to: f32) -> f32 {
+    let mut delta = to - from;
+    if delta > 180.0 {
+        delta -= 360.0;
+    } else if delta <= -180.0 {
+        delta += 360.0;
+    }
+    delta
+}
@@ -6,7 +6,28 @@ use core::mem::{
     MaybeUninit,
 };
 
-static mut MEMORY: [u8; 256] = [0xab; 256];
+// The atmega328p's 2KB of SRAM holds the statics (.data and .bss, which include MEMORY) from the
+// bottom up, and the stack from the top down.  MEMORY gets all of the drivers' futures, which are
+// most of what we allocate; `avr-size -A` on the ELF shows how much the rest of .data and .bss
+// take, and whatever's left over is the stack.  Nothing stops the stack from growing into the
+// statics, so `check_stack_reserve` makes sure at startup that it at least has STACK_RESERVE bytes
+// to work with.
+static mut MEMORY: [u8; 1024] = [0xab; 1024];
+
+const RAMEND: usize = 0x8ff;
+const STACK_RESERVE: usize = 256; // the polls of nested futures plus an interrupt handler or two
+
+extern "C" {
+    // Set by the linker script to the first byte past .bss (and .noinit)
+    static __heap_start: u8;
+}
+
+pub fn check_stack_reserve() {
+    let statics_end = unsafe { &__heap_start as *const u8 as usize };
+    if (RAMEND + 1).saturating_sub(statics_end) < STACK_RESERVE {
+        panic!("statics leave too little SRAM for the stack");
+    }
+}
 
 pub struct Allocator {
     len: usize,
@@ -0,0 +1,175 @@
+// Tuning parameters that can be changed at runtime (over the serial console) instead of being
+// baked in as constants.  Every parameter is stored as an f32; "Int" parameters are rounded when
+// they're set.  Changed values are persisted to the KV store the next time `save_params` is called,
+// which the state machine does every time it changes state.
+use crate::uno::{
+    eeprom::EepromValue,
+    imu::MAG_RING_LEN,
+    ir_sensors::PROFILE_COUNT,
+    kv_store::{
+        kv_record_size,
+        KvError,
+        KvKey,
+        KV_PAGE_CAPACITY,
+    },
+    Uno,
+};
+
+const PARAM_KEY_BASE: KvKey = 0x10;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ParamKind {
+    Float,
+    Int,
+}
+
+pub struct ParamDef {
+    pub name: &'static str,
+    pub kind: ParamKind,
+    pub default: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamError {
+    OutOfRange,
+}
+
+macro_rules! define_params {
+    ($($variant:ident => $name:literal, $kind:ident, $default:expr, $min:expr, $max:expr;)+) => {
+        #[derive(Clone, Copy, PartialEq)]
+        pub enum Param {
+            $($variant),+
+        }
+
+        pub const PARAMS: &[ParamDef] = &[$(ParamDef {
+            name: $name,
+            kind: ParamKind::$kind,
+            default: $default as f32,
+            min: $min as f32,
+            max: $max as f32,
+        }),+];
+
+        const PARAM_LIST: &[Param] = &[$(Param::$variant),+];
+
+        static mut VALUES: [f32; PARAMS.len()] = [$($default as f32),+];
+    };
+}
+
+define_params! {
+    UpdateDelayMs => "upd_ms", Int, 100, 10, 1000;
+    RotationTolerance => "rot_tol", Float, 5.0, 0.5, 45.0;
+    RotationGain => "rot_gain", Float, 0.6, 0.0, 2.0;
+    RotationBaseSpeed => "rot_base", Float, 0.0, 0.0, 1.0;
+    TurnMsPerDegree => "turn_ms_deg", Float, 5.0, 0.5, 50.0;
+    IREdgeThreshold => "ir_thresh", Int, 500, 0, 1000;
+    IREdgeHysteresis => "ir_hyst", Int, 100, 0, 500;
+    IREdgeDebounce => "ir_debounce", Int, 2, 1, 10;
+    IRSampleDelayMs => "ir_ms", Int, 10, 1, 1000;
+    IRAmbientCompensation => "ir_ambient", Int, 0, 0, 1;
+    IRAutoCalibration => "ir_auto", Int, 0, 0, 1;
+    IRSweepMs => "ir_sweep_ms", Int, 3000, 500, 10000;
+    IRMinContrast => "ir_contrast", Int, 200, 0, 2000;
+    ExplorationSpeed => "exp_speed", Float, 0.5, 0.0, 1.0;
+    MaxMotorDelta => "mot_delta", Float, 0.1, 0.01, 1.0;
+    Profile => "profile", Int, 0, 0, PROFILE_COUNT - 1;
+    FusionMagWeight => "fuse_mag", Float, 0.05, 0.0, 1.0;
+    FusionMagWeightDriving => "fuse_mag_mv", Float, 0.005, 0.0, 1.0;
+    MagFilterWindow => "mag_window", Int, 4, 1, MAG_RING_LEN;
+    MagFilterMedian => "mag_median", Int, 0, 0, 1;
+    MagInclination => "mag_dip", Float, 60.0, -80.0, 80.0; // degrees, look it up for your location
+    BumpThreshold => "bump_g", Float, 0.5, 0.1, 4.0;
+    BumpJerkThreshold => "bump_jerk", Float, 20.0, 1.0, 500.0;
+    BumpHoldoffMs => "bump_hold", Int, 500, 0, 5000;
+    MaxTiltDegrees => "tilt_max", Float, 45.0, 10.0, 90.0;
+    TapThreshold => "tap_g", Float, 0.4, 0.1, 4.0;
+    DoubleTapWindowMs => "tap_dbl", Int, 400, 100, 2000;
+}
+
+// Each bit tracks whether the corresponding parameter has changed since it was last saved
+static mut DIRTY: u32 = 0;
+const _: [(); 0] = [(); (PARAMS.len() > 32) as usize]; // one bit per parameter in DIRTY
+
+// Every parameter could be tuned at once, so they all have to fit in the KV store together, along
+// with the other keys (just the run counter)
+const KV_SPACE_NEEDED: usize =
+    PARAMS.len() * kv_record_size(<f32 as EepromValue>::SIZE) + kv_record_size(<u16 as EepromValue>::SIZE);
+const _: [(); 0] = [(); (KV_SPACE_NEEDED > KV_PAGE_CAPACITY) as usize];
+
+impl Param {
+    pub fn def(self) -> &'static ParamDef {
+        &PARAMS[self as usize]
+    }
+
+    pub fn all() -> &'static [Param] {
+        PARAM_LIST
+    }
+
+    pub fn find(name: &[u8]) -> Option<Param> {
+        PARAM_LIST.iter().copied().find(|p| p.def().name.as_bytes() == name)
+    }
+
+    fn key(self) -> KvKey {
+        PARAM_KEY_BASE + self as u8
+    }
+}
+
+pub fn get(param: Param) -> f32 {
+    unsafe { VALUES[param as usize] }
+}
+
+pub fn get_u32(param: Param) -> u32 {
+    get(param) as u32
+}
+
+pub fn set(param: Param, value: f32) -> Result<(), ParamError> {
+    let def = param.def();
+    if !(value >= def.min && value <= def.max) {
+        return Err(ParamError::OutOfRange);
+    }
+
+    let value = match def.kind {
+        ParamKind::Float => value,
+        ParamKind::Int if value < 0.0 => (value - 0.5) as i32 as f32,
+        ParamKind::Int => (value + 0.5) as i32 as f32,
+    };
+    unsafe {
+        VALUES[param as usize] = value;
+        DIRTY |= 1 << param as u8;
+    }
+    Ok(())
+}
+
+pub fn reset(param: Param) {
+    set(param, param.def().default).ok();
+}
+
+impl Uno {
+    // Anything that's missing or out of range in the KV store keeps its default value
+    pub async fn load_params(&mut self) {
+        for &param in Param::all() {
+            if let Ok(value) = self.kv_get::<f32>(param.key()).await {
+                set(param, value).ok();
+            }
+        }
+        unsafe { DIRTY = 0 };
+    }
+
+    pub async fn save_params(&mut self) {
+        let mut full = false;
+        for &param in Param::all() {
+            if unsafe { DIRTY } & (1 << param as u8) == 0 {
+                continue;
+            }
+            match self.kv_put(param.key(), &get(param)).await {
+                Ok(()) => unsafe { DIRTY &= !(1 << param as u8) },
+                Err(KvError::Full) => full = true,
+                Err(_) => (),
+            }
+        }
+        if full {
+            self.console.write_line("some params not saved: kv store full");
+        }
+    }
+}
@@ -0,0 +1,165 @@
+// A fixed-size ring of timestamped 3-axis sensor samples, with the filters we use to smooth them.
+use crate::math::Vector3;
+
+pub type RawSample = (i16, i16, i16);
+
+#[derive(Clone, Copy)]
+pub struct Sample {
+    pub timestamp_ms: u32,
+    pub value: RawSample,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FilterKind {
+    MovingAverage,
+    Median, // per axis, which throws out the occasional spike from the motors
+}
+
+pub struct SampleRing<const N: usize> {
+    samples: [Sample; N],
+    next: usize,
+    len: usize,
+}
+
+impl<const N: usize> SampleRing<N> {
+    pub fn new() -> SampleRing<N> {
+        SampleRing {
+            samples: [Sample {
+                timestamp_ms: 0,
+                value: (0, 0, 0),
+            }; N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    // Overwrites the oldest sample once the ring is full
+    pub fn push(&mut self, timestamp_ms: u32, value: RawSample) {
+        self.samples[self.next] = Sample { timestamp_ms, value };
+        self.next = (self.next + 1) % N;
+        if self.len < N {
+            self.len += 1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn latest(&self) -> Option<Sample> {
+        self.recent(1).next()
+    }
+
+    // Up to `count` of the most recent samples, newest first
+    pub fn recent(&self, count: usize) -> impl Iterator<Item = Sample> + '_ {
+        let count = if count < self.len { count } else { self.len };
+        (0..count).map(move |i| self.samples[(self.next + N - 1 - i) % N])
+    }
+
+    // Filter the most recent `window` samples; None if the ring is empty
+    pub fn filter(&self, kind: FilterKind, window: usize) -> Option<Vector3> {
+        let window = if window < self.len { window } else { self.len };
+        if window == 0 {
+            return None;
+        }
+
+        match kind {
+            FilterKind::MovingAverage => {
+                let mut sum = (0.0, 0.0, 0.0);
+                for sample in self.recent(window) {
+                    let (x, y, z) = sample.value;
+                    sum = (sum.0 + x as f32, sum.1 + y as f32, sum.2 + z as f32);
+                }
+                let n = window as f32;
+                Some((sum.0 / n, sum.1 / n, sum.2 / n))
+            },
+            FilterKind::Median => Some((
+                self.median(window, |v| v.0),
+                self.median(window, |v| v.1),
+                self.median(window, |v| v.2),
+            )),
+        }
+    }
+
+    fn median(&self, window: usize, axis: fn(RawSample) -> i16) -> f32 {
+        // Insertion sort; the window is never more than a handful of samples
+        let mut sorted = [0i16; N];
+        for (i, sample) in self.recent(window).enumerate() {
+            let value = axis(sample.value);
+            let mut j = i;
+            while j > 0 && sorted[j - 1] > value {
+                sorted[j] = sorted[j - 1];
+                j -= 1;
+            }
+            sorted[j] = value;
+        }
+
+        if window % 2 == 1 {
+            sorted[window / 2] as f32
+        } else {
+            (sorted[window / 2 - 1] as f32 + sorted[window / 2] as f32) / 2.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring_of<const N: usize>(values: &[RawSample]) -> SampleRing<N> {
+        let mut ring = SampleRing::new();
+        for (i, &value) in values.iter().enumerate() {
+            ring.push(10 * i as u32, value);
+        }
+        ring
+    }
+
+    #[test]
+    fn empty_ring() {
+        let ring = SampleRing::<4>::new();
+        assert_eq!(ring.len(), 0);
+        assert!(ring.latest().is_none());
+        assert!(ring.filter(FilterKind::MovingAverage, 4).is_none());
+        assert!(ring.filter(FilterKind::Median, 4).is_none());
+    }
+
+    #[test]
+    fn wraps_around_keeping_the_newest() {
+        let ring = ring_of::<4>(&[(1, 0, 0), (2, 0, 0), (3, 0, 0), (4, 0, 0), (5, 0, 0), (6, 0, 0)]);
+        assert_eq!(ring.len(), 4);
+
+        let latest = ring.latest().unwrap();
+        assert_eq!(latest.value, (6, 0, 0));
+        assert_eq!(latest.timestamp_ms, 50);
+
+        let recent: Vec<i16> = ring.recent(10).map(|sample| sample.value.0).collect();
+        assert_eq!(recent, vec![6, 5, 4, 3]);
+        let recent: Vec<i16> = ring.recent(2).map(|sample| sample.value.0).collect();
+        assert_eq!(recent, vec![6, 5]);
+    }
+
+    #[test]
+    fn moving_average() {
+        let ring = ring_of::<8>(&[(100, 0, 0), (1, -2, 3), (3, -4, 5), (5, -6, 7)]);
+        assert_eq!(ring.filter(FilterKind::MovingAverage, 3), Some((3.0, -4.0, 5.0)));
+
+        // A window bigger than the ring just uses everything there is
+        assert_eq!(ring.filter(FilterKind::MovingAverage, 8), Some((27.25, -3.0, 3.75)));
+    }
+
+    #[test]
+    fn median_throws_out_spikes() {
+        let ring = ring_of::<8>(&[(10, 5, -1), (12, 5, -3), (900, 4, -2), (11, -800, -4), (13, 6, 700)]);
+        assert_eq!(ring.filter(FilterKind::Median, 5), Some((12.0, 5.0, -2.0)));
+
+        // Even windows average the middle two
+        assert_eq!(ring.filter(FilterKind::Median, 4), Some((12.5, 4.5, -2.5)));
+    }
+
+    #[test]
+    fn filters_only_see_samples_still_in_the_ring() {
+        let ring = ring_of::<3>(&[(1000, 1000, 1000), (1, 2, 3), (3, 4, 5), (5, 6, 7)]);
+        assert_eq!(ring.filter(FilterKind::MovingAverage, 3), Some((3.0, 4.0, 5.0)));
+        assert_eq!(ring.filter(FilterKind::Median, 3), Some((3.0, 4.0, 5.0)));
+    }
+}
@@ -1,53 +1,146 @@
 use crate::{
     avr_async::Waiter,
+    params::{
+        self,
+        Param,
+    },
     state_machine::State,
     uno::{
+        active_ir_profile,
         eeprom::*,
+        imu::ImuError,
+        ir_sensors::{
+            CalibrationSweep,
+            IRCalibrationVector,
+            IRReadMode,
+        },
         motor,
         Uno,
+        IR_SENSOR_COUNT,
     },
 };
 use arduino_uno::prelude::*;
 
+const IR_SWEEP_SPEED: f32 = 0.3; // slow enough that every sensor gets a few samples on the edge
+const MIN_MAG_SECTORS: u8 = 7; // a fit from much less than a full turn extrapolates the ellipse
+
 pub async fn calibration_future(uno: &mut Uno) -> State {
     // Calibrate the IMU
     uno.blink(3, 500).await;
+    imu_self_test(uno).await;
 
     uno.motor_controller.set_targets(-1.0, 1.0);
-    let (x_min, x_max, y_min, y_max) = uno.imu.get_calibration_vector().await;
+    let mag_calibration = uno.imu.calibrate_magnetometer().await;
     uno.motor_controller.set_targets(0.0, 0.0);
 
-    uno.write_eeprom_u16(IMU_X_MIN_ADDR, x_min as u16).await;
-    uno.write_eeprom_u16(IMU_X_MAX_ADDR, x_max as u16).await;
-    uno.write_eeprom_u16(IMU_Y_MIN_ADDR, y_min as u16).await;
-    uno.write_eeprom_u16(IMU_Y_MAX_ADDR, y_max as u16).await;
+    // If the fit failed we keep whatever calibration we had before
+    match mag_calibration {
+        Ok(Some((_, quality))) if quality.sectors_covered < MIN_MAG_SECTORS => {
+            uno.console.write_value("mag_sectors", quality.sectors_covered as f32);
+            uno.console.write_line("mag calibration failed: incomplete turn");
+        },
+        Ok(Some((calibration, quality))) => {
+            uno.console.write_value("mag_residual", quality.residual);
+            uno.console.write_value("mag_sectors", quality.sectors_covered as f32);
+            uno.eeprom
+                .write(MAG_CALIBRATION, &calibration)
+                .await
+                .expect("write failed");
+            uno.imu.set_mag_calibration(calibration);
+        },
+        Ok(None) => uno.console.write_line("mag calibration failed"),
+        Err(_) => uno.console.write_line("mag calibration failed: imu error"),
+    }
+
+    // The gyro bias has to be measured with the robot sitting still
+    Waiter::new(500).await; // It takes ~400ms for the motors to fully stop
+    match uno.gyro.calibrate_bias().await {
+        Ok(gyro_bias) => uno.eeprom.write(GYRO_BIAS, &gyro_bias).await.expect("write failed"),
+        Err(_) => uno.console.write_line("gyro calibration failed: imu error"),
+    }
+
+    // calibrate the IR sensors for the active profile; if the sweep fails we keep whatever
+    // calibration we had before
+    let ir_calibration_vector = if params::get_u32(Param::IRAutoCalibration) != 0 {
+        sweep_ir_calibration(uno).await
+    } else {
+        Some(manual_ir_calibration(uno).await)
+    };
+    if let Some(ir_calibration_vector) = ir_calibration_vector {
+        uno.eeprom
+            .write(active_ir_profile(), &ir_calibration_vector)
+            .await
+            .expect("write failed");
+    }
+    uno.eeprom.flush().await;
+
+    uno.blink(3, 500).await;
+
+    State::Initialization
+}
 
-    // calibrate the IR sensors -- dark first, then light
-    // wait for a button press to signal that the robot is positioned
-    // over a dark (light) surface
+// Dark first, then light; wait for a button press to signal that the robot is positioned over a
+// dark (light) surface
+async fn manual_ir_calibration(uno: &mut Uno) -> IRCalibrationVector<IR_SENSOR_COUNT> {
     uno.blink(3, 500).await;
 
     uno.pushbutton.wait_for_press().await;
-    let [s0_max, s1_max, s2_max, s3_max, s4_max, s5_max] = uno.ir_sensors.calibrate(&mut uno.ddr, true).await;
-    uno.write_eeprom_u16(IR_0_MAX_ADDR, s0_max as u16).await;
-    uno.write_eeprom_u16(IR_1_MAX_ADDR, s1_max as u16).await;
-    uno.write_eeprom_u16(IR_2_MAX_ADDR, s2_max as u16).await;
-    uno.write_eeprom_u16(IR_3_MAX_ADDR, s3_max as u16).await;
-    uno.write_eeprom_u16(IR_4_MAX_ADDR, s4_max as u16).await;
-    uno.write_eeprom_u16(IR_5_MAX_ADDR, s5_max as u16).await;
+    let max_values = uno.ir_sensors.calibrate(true, IRReadMode::from_params()).await;
 
     uno.blink(3, 500).await;
 
     uno.pushbutton.wait_for_press().await;
-    let [s0_min, s1_min, s2_min, s3_min, s4_min, s5_min] = uno.ir_sensors.calibrate(&mut uno.ddr, false).await;
-    uno.write_eeprom_u16(IR_0_MIN_ADDR, s0_min as u16).await;
-    uno.write_eeprom_u16(IR_1_MIN_ADDR, s1_min as u16).await;
-    uno.write_eeprom_u16(IR_2_MIN_ADDR, s2_min as u16).await;
-    uno.write_eeprom_u16(IR_3_MIN_ADDR, s3_min as u16).await;
-    uno.write_eeprom_u16(IR_4_MIN_ADDR, s4_min as u16).await;
-    uno.write_eeprom_u16(IR_5_MIN_ADDR, s5_min as u16).await;
+    let min_values = uno.ir_sensors.calibrate(false, IRReadMode::from_params()).await;
 
+    let mut ir_calibration_vector = [(0, 0); IR_SENSOR_COUNT];
+    for i in 0..ir_calibration_vector.len() {
+        ir_calibration_vector[i] = (min_values[i], max_values[i]);
+    }
+    ir_calibration_vector
+}
+
+// Wait for a button press to signal that the robot is sitting across the edge, then turn in place
+// one way and back again so that every sensor passes over both surfaces
+async fn sweep_ir_calibration(uno: &mut Uno) -> Option<IRCalibrationVector<IR_SENSOR_COUNT>> {
     uno.blink(3, 500).await;
+    uno.pushbutton.wait_for_press().await;
 
-    State::Initialization
+    let half_sweep_ms = params::get_u32(Param::IRSweepMs) / 2;
+    let mode = IRReadMode::from_params();
+    let mut sweep = CalibrationSweep::new();
+    uno.motor_controller.set_targets(IR_SWEEP_SPEED, -IR_SWEEP_SPEED);
+    uno.ir_sensors.sweep(&mut sweep, half_sweep_ms, mode).await;
+    uno.motor_controller.set_targets(-IR_SWEEP_SPEED, IR_SWEEP_SPEED);
+    uno.ir_sensors.sweep(&mut sweep, half_sweep_ms, mode).await;
+    uno.motor_controller.set_targets(0.0, 0.0);
+
+    let min_contrast = params::get_u32(Param::IRMinContrast) as u16;
+    let mut passed = true;
+    for i in 0..IR_SENSOR_COUNT {
+        if !sweep.has_contrast(i, min_contrast) {
+            uno.console.write_value("ir_low_contrast", i as f32);
+            passed = false;
+        }
+    }
+
+    if passed {
+        Some(sweep.calibration_vector())
+    } else {
+        uno.console.write_line("ir calibration failed");
+        uno.blink(5, 100).await;
+        None
+    }
+}
+
+// The robot is still sitting still at this point, which the self-tests need
+async fn imu_self_test(uno: &mut Uno) {
+    match uno.imu.self_test().await {
+        Ok(results) => {
+            for result in results.iter().flatten() {
+                uno.console.write_pass_fail(result.name, result.passed);
+            }
+        },
+        Err(ImuError::NotDetected) => uno.console.write_line("imu not detected"),
+        Err(_) => uno.console.write_line("imu self-test failed: imu error"),
+    }
 }
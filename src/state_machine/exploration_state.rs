@@ -1,33 +1,74 @@
 use crate::{
     avr_async::Waiter,
-    state_machine,
+    params::{
+        self,
+        Param,
+    },
     state_machine::State,
     uno::{
+        bump::BumpDirection,
+        edge::EdgeKind,
+        ir_sensors::IRReadMode,
         MotorController,
         Uno,
     },
 };
 
-pub async fn exploration_future(uno: &mut Uno, found_edge: bool) -> State {
-    if found_edge {
-        uno.motor_controller.set_targets(-0.5, -0.5);
+// `edge` is the side we saw the edge on, if we're backing away from one
+pub async fn exploration_future(uno: &mut Uno, edge: Option<EdgeKind>) -> State {
+    let speed = params::get(Param::ExplorationSpeed);
+    if edge.is_some() {
+        uno.motor_controller.set_targets(-speed, -speed);
     } else {
-        uno.motor_controller.set_targets(0.5, 0.5);
+        uno.motor_controller.set_targets(speed, speed);
+    }
+
+    // Anything we ran into before we started exploring again is old news, but we might have
+    // stopped on (or already backed off of) an edge
+    uno.bump.take_event();
+    uno.ir_sensors.take_edge_event();
+    uno.ir_sensors.set_mode(IRReadMode::from_params());
+    if let Some(state) = edge_transition(edge, uno.ir_sensors.edge()) {
+        return state;
     }
 
     loop {
-        uno.ir_sensors.read_calibrated(&mut uno.ddr).await;
-        let triggered_count = uno.ir_sensors.values.iter().filter(|&&x| x > 500).count();
+        // Turn away from whatever we hit; positive angles are clockwise
+        if let Some(bump) = uno.bump.take_event() {
+            match bump.direction {
+                BumpDirection::Front => return State::Rotation { angle: 180.0 },
+                BumpDirection::Left => return State::Rotation { angle: 90.0 },
+                BumpDirection::Right => return State::Rotation { angle: -90.0 },
+                BumpDirection::Back if edge.is_some() => return State::Rotation { angle: 90.0 },
+                BumpDirection::Back => (), // we're driving away from it anyways
+            }
+        }
 
-        if triggered_count > 1 {
-            if !found_edge {
-                return State::Exploration { found_edge: true };
+        if let Some(event) = uno.ir_sensors.take_edge_event() {
+            if let Some(state) = edge_transition(edge, event.kind) {
+                return state;
             }
-        } else if found_edge {
-            // we've moved off the boundary, so now we rotate 90 degrees
-            return State::Rotation { angle: 90.0 };
         }
 
-        Waiter::new(state_machine::UPDATE_DELAY_MS).await;
+        Waiter::new(params::get_u32(Param::UpdateDelayMs)).await;
+    }
+}
+
+fn edge_transition(edge: Option<EdgeKind>, kind: EdgeKind) -> Option<State> {
+    match edge {
+        None if kind != EdgeKind::Cleared => Some(State::Exploration { edge: Some(kind) }),
+        // we've moved off the boundary, so now we turn away from the side it was on
+        Some(side) if kind == EdgeKind::Cleared => Some(State::Rotation {
+            angle: escape_angle(side),
+        }),
+        _ => None,
+    }
+}
+
+fn escape_angle(side: EdgeKind) -> f32 {
+    match side {
+        EdgeKind::LeftEdge => 90.0,
+        EdgeKind::RightEdge => -90.0,
+        EdgeKind::FrontEdge | EdgeKind::Cleared => 135.0,
     }
 }
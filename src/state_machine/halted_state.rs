@@ -0,0 +1,25 @@
+use crate::{
+    state_machine::State,
+    uno::Uno,
+};
+
+// The orientation cutoff tripped, so the motors are locked off.  Once the robot is back on its
+// wheels, a button press picks up where we left off.
+pub async fn halted_future(uno: &mut Uno, recalibrate: bool) -> State {
+    loop {
+        uno.pushbutton.wait_for_press().await;
+        if uno.orientation.is_upright() {
+            break;
+        }
+
+        // Still not upright; flash the LED to say so
+        uno.blink(5, 100).await;
+    }
+
+    uno.orientation.reset();
+    if recalibrate {
+        State::Calibration
+    } else {
+        State::Exploration { edge: None }
+    }
+}
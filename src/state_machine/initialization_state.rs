@@ -1,7 +1,17 @@
 use crate::{
-    avr_async::Waiter,
+    avr_async::{
+        select,
+        Either,
+        Waiter,
+    },
+    params::{
+        self,
+        Param,
+    },
     state_machine::State,
     uno::{
+        ir_sensors::PROFILE_COUNT,
+        kv_store::KV_RUN_COUNT,
         timers,
         MotorController,
         Uno,
@@ -9,15 +19,49 @@ use crate::{
 };
 use arduino_uno::prelude::*;
 
+const PROFILE_EXTRA_PRESSES: u8 = 1;
 const CONFIG_EXTRA_PRESSES: u8 = 2;
+const PROFILE_SELECT_WINDOW_MS: u32 = 2000;
 
 pub async fn initialization_future(uno: &mut Uno) -> State {
-    uno.pushbutton.wait_for_press().await;
-    let additional_button_presses = uno.pushbutton.count_presses_before(timers::millis() + 1000).await;
-    if additional_button_presses >= CONFIG_EXTRA_PRESSES {
+    // A tap on the robot starts a run just like a single press; picking a profile or recalibrating
+    // still takes the button
+    let additional_button_presses = match select(uno.pushbutton.wait_for_press(), uno.taps.wait_for_tap()).await {
+        Either::Left(()) => uno.pushbutton.count_presses_before(timers::millis() + 1000).await,
+        Either::Right(_) => 0,
+    };
+    if additional_button_presses == PROFILE_EXTRA_PRESSES {
+        select_profile(uno).await;
+        return State::Initialization;
+    }
+
+    // If the last calibration was interrupted partway through, the stored values are garbage
+    if additional_button_presses >= CONFIG_EXTRA_PRESSES
+        || !uno.eeprom.is_consistent().await
+        || !uno.load_calibration_data().await
+    {
         State::Calibration
     } else {
-        uno.load_calibration_data().await;
-        State::Exploration { found_edge: false }
+        let run_count: u16 = uno.kv_get(KV_RUN_COUNT).await.unwrap_or(0);
+        uno.kv_put(KV_RUN_COUNT, &run_count.wrapping_add(1)).await.ok();
+        State::Exploration { edge: None }
+    }
+}
+
+// Blink out the number of the current profile, then count button presses to choose a new one (one
+// press selects the first profile, two the second, and so on).  The choice is saved right away so
+// it's remembered across resets.
+async fn select_profile(uno: &mut Uno) {
+    Waiter::new(500).await;
+    uno.blink(params::get_u32(Param::Profile) as u8 + 1, 250).await;
+
+    let presses = uno
+        .pushbutton
+        .count_presses_before(timers::millis() + PROFILE_SELECT_WINDOW_MS)
+        .await;
+    if presses > 0 && presses as usize <= PROFILE_COUNT {
+        params::set(Param::Profile, (presses - 1) as f32).ok();
+        uno.save_params().await;
+        uno.blink(presses, 250).await;
     }
 }
@@ -1,42 +1,101 @@
 mod calibration_state;
 mod exploration_state;
+mod halted_state;
 mod initialization_state;
 mod rotation_state;
 
 use self::{
     calibration_state::calibration_future,
     exploration_state::exploration_future,
+    halted_state::halted_future,
     initialization_state::initialization_future,
     rotation_state::rotation_future,
 };
 use crate::{
     mem::Allocator,
-    uno::MotorController,
+    uno::{
+        edge::EdgeKind,
+        orientation::OrientationMonitor,
+        MotorController,
+    },
     Uno,
 };
 use core::{
     cell::RefCell,
     future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
 };
 
-pub const UPDATE_DELAY_MS: u32 = 100;
-
 pub enum State {
     Calibration,
-    Exploration { found_edge: bool },
+    Exploration { edge: Option<EdgeKind> },
+    Halted { recalibrate: bool },
     Initialization,
     Rotation { angle: f32 },
 }
 
 pub fn build_state_machine(uno: &'static mut Uno) -> &'static mut dyn Future<Output = !> {
     let mut current_state = State::Initialization;
-    let future = async move || loop {
-        current_state = match current_state {
-            State::Calibration => calibration_future(uno).await,
-            State::Exploration { found_edge } => exploration_future(uno, found_edge).await,
-            State::Initialization => initialization_future(uno).await,
-            State::Rotation { angle } => rotation_future(uno, angle).await,
-        };
+    let future = async move || {
+        uno.load_params().await;
+        loop {
+            // Parameters changed over the console are saved here, so they survive a reset once the
+            // robot has moved on to another state
+            uno.save_params().await;
+
+            // Any state that drives the motors gets cut off as soon as the orientation monitor
+            // trips; an interrupted calibration has to be started over
+            let orientation = uno.orientation;
+            current_state = match current_state {
+                State::Initialization => initialization_future(uno).await,
+                State::Halted { recalibrate } => halted_future(uno, recalibrate).await,
+                state => {
+                    let recalibrate = matches!(state, State::Calibration);
+                    Preemptible {
+                        future: run_state(uno, state),
+                        orientation,
+                    }
+                    .await
+                    .unwrap_or(State::Halted { recalibrate })
+                },
+            };
+        }
     };
     Allocator::get().new(future())
 }
+
+async fn run_state(uno: &mut Uno, state: State) -> State {
+    match state {
+        State::Calibration => calibration_future(uno).await,
+        State::Exploration { edge } => exploration_future(uno, edge).await,
+        State::Halted { recalibrate } => halted_future(uno, recalibrate).await,
+        State::Initialization => initialization_future(uno).await,
+        State::Rotation { angle } => rotation_future(uno, angle).await,
+    }
+}
+
+// Resolves with None (dropping the wrapped future) if the orientation monitor trips first.  The
+// future can be dropped at any await, so whatever a state holds across one (the I2C bus, the IMU's
+// calibrating flag) has to be released by a guard rather than at the end of the function.
+struct Preemptible<F: Future<Output = State>> {
+    future: F,
+    orientation: &'static OrientationMonitor,
+}
+
+impl<F: Future<Output = State>> Future for Preemptible<F> {
+    type Output = Option<State>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        if self.orientation.is_tripped() {
+            return Poll::Ready(None);
+        }
+        self.orientation.register_waker(ctx.waker());
+
+        // We never move the wrapped future out of self, so it's safe to pin it
+        unsafe { self.map_unchecked_mut(|s| &mut s.future) }.poll(ctx).map(Some)
+    }
+}
@@ -1,6 +1,10 @@
 use crate::{
     avr_async::Waiter,
-    state_machine,
+    math,
+    params::{
+        self,
+        Param,
+    },
     state_machine::State,
     uno::{
         motor,
@@ -9,49 +13,58 @@ use crate::{
 };
 use micromath::F32Ext;
 
-const TOLERANCE: f32 = 5.0;
-const BASE_SPEED: f32 = 0.0;
-const ROTATION_UPDATE_MS: u32 = 20; // The IMU's output data rate is 50Hz, or 1 / 20ms.
-
-fn degrees_delta(heading_from: f32, heading_to: f32) -> f32 {
-    let mut delta = heading_to - heading_from;
-    if delta > 180.0 {
-        delta -= 360.0;
-    } else if delta < -180.0 {
-        delta += 360.0;
-    }
-
-    delta
-}
+const ROTATION_UPDATE_MS: u32 = 20; // The heading estimate updates at 50Hz, or 1 / 20ms.
+const TIMED_ROTATION_SPEED: f32 = 0.5; // The "turn_ms_deg" parameter is measured at this speed
 
 pub async fn rotation_future(uno: &mut Uno, angle: f32) -> State {
-    // Turn off the motors to reduce interference
-    uno.motor_controller.set_targets(0.0, 0.0);
-    Waiter::new(500).await; // It takes ~400ms for the motors to fully stop
-
-    let mut new_heading = uno.imu.get_current_heading_degrees() + angle;
-    if new_heading > 360.0 {
-        new_heading -= 360.0;
+    // Without the gyro the heading isn't going anywhere, so the best we can do is turn for about
+    // the right amount of time
+    if !uno.heading.is_tracking() {
+        timed_rotation(uno, angle).await;
+        return State::Exploration { edge: None };
     }
 
+    // The fused heading is good even while the motors are running, so we don't need to stop first
+    let new_heading = math::wrap_degrees(uno.heading.heading_degrees() + angle);
+
     loop {
-        let delta = degrees_delta(uno.imu.get_current_heading_degrees(), new_heading);
-        if delta <= TOLERANCE {
+        let delta = math::degrees_delta(uno.heading.heading_degrees(), new_heading);
+        if !uno.heading.is_tracking() {
+            timed_rotation(uno, delta).await;
+            break;
+        }
+
+        // Overshooting shows up as the delta changing sign near 0 (rather than wrapping at 180)
+        let overshot = delta * angle < 0.0 && delta.abs() < 90.0;
+        if delta.abs() <= params::get(Param::RotationTolerance) || overshot {
             uno.motor_controller.set_targets(0.0, 0.0);
             Waiter::new(100).await;
             break;
         }
 
-        let mut speed = 0.6 * delta / 180.0;
+        let base_speed = params::get(Param::RotationBaseSpeed);
+        let mut speed = params::get(Param::RotationGain) * delta / 180.0;
         if speed < 0.0 {
-            speed -= BASE_SPEED;
+            speed -= base_speed;
         } else {
-            speed += BASE_SPEED;
+            speed += base_speed;
         }
 
         uno.motor_controller.set_targets(speed, -speed);
         Waiter::new(ROTATION_UPDATE_MS).await;
     }
 
-    return State::Exploration { found_edge: false };
+    return State::Exploration { edge: None };
+}
+
+async fn timed_rotation(uno: &mut Uno, angle: f32) {
+    let speed = if angle < 0.0 {
+        -TIMED_ROTATION_SPEED
+    } else {
+        TIMED_ROTATION_SPEED
+    };
+    uno.motor_controller.set_targets(speed, -speed);
+    Waiter::new((angle.abs() * params::get(Param::TurnMsPerDegree)) as u32).await;
+    uno.motor_controller.set_targets(0.0, 0.0);
+    Waiter::new(100).await;
 }
@@ -0,0 +1,108 @@
+// An interrupt-driven driver for the analog-to-digital converter.  A conversion takes 13 ADC
+// clocks, or about 100us with the ADC clocked at 125kHz, so it's worth letting other tasks run in
+// the meantime: we start the conversion and sleep until the ADC interrupt says it's done.
+use crate::{
+    avr_async::Waiter,
+    mem::Allocator,
+    util::*,
+};
+use avr_hal_generic::{
+    avr_device,
+    avr_device::interrupt::free as critical_section,
+};
+use core::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    ptr::{
+        read_volatile,
+        write_volatile,
+    },
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+};
+
+const ADEN: u8 = 0x80; // ADC enable bit in ADCSRA
+const ADSC: u8 = 0x40; // start conversion; stays set until the conversion finishes
+const ADIE: u8 = 0x08; // conversion complete interrupt enable
+const ADPS_128: u8 = 0x07; // 16MHz / 128 = 125kHz, inside the 50-200kHz needed for full resolution
+const REFS_AVCC: u8 = 0x40; // measure against AVcc, which is tied to 5V on the Uno
+const MUX_MASK: u8 = 0x0f;
+pub const MAX_ADC_VALUE: u16 = 1023;
+
+static mut ADC_WAKER: Option<Waker> = None;
+
+pub struct Adc {
+    busy: Cell<bool>,
+}
+
+impl Adc {
+    // Like the MotorController, this is a static reference so it can be shared between drivers
+    pub fn new() -> &'static Adc {
+        unsafe {
+            write_volatile(ADMUX, REFS_AVCC);
+            write_volatile(ADCSRA, ADEN | ADPS_128);
+        }
+        Allocator::get().new(Adc { busy: Cell::new(false) })
+    }
+
+    // Channels 0-5 are A0-A5; for pins only used as analog inputs, turning off the digital input
+    // buffer saves a bit of power and noise
+    pub fn disable_digital_input(&self, channel: u8) {
+        if channel < 6 {
+            unsafe { write_volatile(DIDR0, read_volatile(DIDR0) | 1 << channel) };
+        }
+    }
+
+    pub async fn read(&self, channel: u8) -> u16 {
+        // There's only the one converter, so anyone else has to wait their turn
+        while self.busy.get() {
+            Waiter::new(0).await;
+        }
+        self.busy.set(true);
+
+        unsafe {
+            write_volatile(ADMUX, REFS_AVCC | (channel & MUX_MASK));
+            write_volatile(ADCSRA, ADEN | ADSC | ADIE | ADPS_128);
+        }
+        Conversion.await;
+
+        // ADCL has to be read first; that locks the result until ADCH is read
+        let value = unsafe { read_volatile(ADCL) as u16 | (read_volatile(ADCH) as u16) << 8 };
+        self.busy.set(false);
+        value
+    }
+}
+
+// Resolves once the current conversion finishes
+struct Conversion;
+
+impl Future for Conversion {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let done = critical_section(|_| unsafe {
+            if read_volatile(ADCSRA) & ADSC == 0 {
+                return true;
+            }
+            ADC_WAKER = Some(ctx.waker().clone());
+            false
+        });
+        if done {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[avr_device::interrupt(atmega328p)]
+unsafe fn ADC() {
+    // The hardware clears the interrupt flag for us when the ISR runs
+    if let Some(waker) = ADC_WAKER.take() {
+        waker.wake();
+    }
+}
@@ -0,0 +1,79 @@
+// Collision detection from the accelerometer.  A bump shows up as a sudden jump in horizontal
+// acceleration: a big change from one sample to the next (jerk) that takes the reading well away
+// from the slowly-tracked baseline (gravity, plus whatever tilt the robot has).  Requiring both
+// keeps us from triggering on the motors ramping up and down, which is gradual, and on vibration,
+// which is small.
+//
+// The BumpDetector lives in bump_detector.rs so it can be fed recorded traces on the host; the
+// BumpMonitor driver feeds it live samples and holds onto the latest event for the states.
+pub use crate::uno::bump_detector::{
+    BumpConfig,
+    BumpDetector,
+    BumpDirection,
+    BumpEvent,
+};
+use crate::{
+    mem::Allocator,
+    params::{
+        self,
+        Param,
+    },
+    uno::imu::IMU,
+};
+use core::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    future::Future,
+};
+
+impl BumpConfig {
+    pub fn from_params() -> BumpConfig {
+        BumpConfig {
+            threshold_g: params::get(Param::BumpThreshold),
+            jerk_threshold_g_per_s: params::get(Param::BumpJerkThreshold),
+            holdoff_ms: params::get_u32(Param::BumpHoldoffMs),
+        }
+    }
+}
+
+pub struct BumpMonitor {
+    imu: &'static IMU,
+    detector: RefCell<BumpDetector>,
+    event: Cell<Option<BumpEvent>>,
+}
+
+impl BumpMonitor {
+    // Like the MotorController, this is a static reference because the driver holds onto "self"
+    pub fn new(imu: &'static IMU) -> &'static BumpMonitor {
+        Allocator::get().new(BumpMonitor {
+            imu,
+            detector: RefCell::new(BumpDetector::new()),
+            event: Cell::new(None),
+        })
+    }
+
+    // The most recent bump that nobody has looked at yet
+    pub fn take_event(&self) -> Option<BumpEvent> {
+        self.event.take()
+    }
+
+    pub fn get_bump_driver(&'static self) -> &'static mut dyn Future<Output = !> {
+        let future = async move || {
+            let mut sequence = 0;
+            loop {
+                // If the IMU is down this waits until the heading estimator brings it back
+                let sample = self.imu.next_accelerometer(sequence).await;
+                sequence = sample.sequence;
+
+                let config = BumpConfig::from_params();
+                let mut detector = self.detector.borrow_mut();
+                if let Some(event) = detector.update(sample.timestamp_ms, sample.value, &config) {
+                    self.event.set(Some(event));
+                }
+            }
+        };
+        Allocator::get().new(future())
+    }
+}
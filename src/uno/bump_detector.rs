@@ -0,0 +1,216 @@
+// The part of collision detection that doesn't touch the hardware, kept apart from the BumpMonitor
+// driver so that it can be fed recorded traces on the host.  See bump.rs for how a bump is told
+// apart from the motors and from vibration.
+use crate::math::{
+    self,
+    Vector3,
+};
+use micromath::F32Ext;
+
+const BASELINE_ALPHA: f32 = 0.05;
+
+// Which side of the robot got hit; the accelerometer's x-axis points forward and its y-axis points
+// left
+#[derive(Clone, Copy, PartialEq)]
+pub enum BumpDirection {
+    Front,
+    Back,
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy)]
+pub struct BumpEvent {
+    pub direction: BumpDirection,
+    pub magnitude_g: f32,
+    pub timestamp_ms: u32,
+}
+
+pub struct BumpConfig {
+    pub threshold_g: f32,
+    pub jerk_threshold_g_per_s: f32,
+    pub holdoff_ms: u32, // ignore the ringing after a bump
+}
+
+pub struct BumpDetector {
+    baseline: Vector3,
+    last: Option<(u32, Vector3)>,
+    last_event_ms: Option<u32>,
+}
+
+impl BumpDetector {
+    pub fn new() -> BumpDetector {
+        BumpDetector {
+            baseline: (0.0, 0.0, 0.0),
+            last: None,
+            last_event_ms: None,
+        }
+    }
+
+    // `accel` is in g
+    pub fn update(&mut self, timestamp_ms: u32, accel: Vector3, config: &BumpConfig) -> Option<BumpEvent> {
+        let (last_ms, last_accel) = match self.last.replace((timestamp_ms, accel)) {
+            Some(last) => last,
+            None => {
+                self.baseline = accel;
+                return None;
+            },
+        };
+
+        let dt = timestamp_ms.wrapping_sub(last_ms).max(1) as f32 / 1000.0;
+        let step = math::sub(accel, last_accel);
+        let jerk = (step.0 * step.0 + step.1 * step.1).sqrt() / dt;
+
+        let (dx, dy, _) = math::sub(accel, self.baseline);
+        let magnitude = (dx * dx + dy * dy).sqrt();
+        if magnitude <= config.threshold_g {
+            // Only track the baseline while things are calm, so a bump doesn't leak into it
+            self.baseline = math::add(
+                self.baseline,
+                math::scale(math::sub(accel, self.baseline), BASELINE_ALPHA),
+            );
+            return None;
+        }
+
+        if jerk < config.jerk_threshold_g_per_s {
+            return None;
+        }
+        // The millisecond clock wraps after ~50 days, so compare elapsed times rather than deadlines
+        if let Some(last_event_ms) = self.last_event_ms {
+            if timestamp_ms.wrapping_sub(last_event_ms) < config.holdoff_ms {
+                return None;
+            }
+        }
+        self.last_event_ms = Some(timestamp_ms);
+
+        // Getting hit on the front pushes the robot backwards, and so on
+        let direction = if dx.abs() >= dy.abs() {
+            if dx < 0.0 {
+                BumpDirection::Front
+            } else {
+                BumpDirection::Back
+            }
+        } else if dy < 0.0 {
+            BumpDirection::Left
+        } else {
+            BumpDirection::Right
+        };
+
+        Some(BumpEvent {
+            direction,
+            magnitude_g: magnitude,
+            timestamp_ms,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_MS: u32 = 20;
+
+    // The parameter defaults
+    const CONFIG: BumpConfig = BumpConfig {
+        threshold_g: 0.5,
+        jerk_threshold_g_per_s: 20.0,
+        holdoff_ms: 500,
+    };
+
+    // Feeds a trace of horizontal (x, y) readings, one every SAMPLE_MS starting at `start_ms`, with
+    // gravity on z, and returns every event along with the index of the sample that raised it
+    fn run(start_ms: u32, trace: &[(f32, f32)]) -> Vec<(usize, BumpEvent)> {
+        let mut detector = BumpDetector::new();
+        let mut events = Vec::new();
+        for (i, &(x, y)) in trace.iter().enumerate() {
+            let timestamp_ms = start_ms.wrapping_add(i as u32 * SAMPLE_MS);
+            if let Some(event) = detector.update(timestamp_ms, (x, y, 1.0), &CONFIG) {
+                events.push((i, event));
+            }
+        }
+        events
+    }
+
+    fn calm(count: usize) -> Vec<(f32, f32)> {
+        vec![(0.0, 0.0); count]
+    }
+
+    // A sharp spike followed by some ringing that dies out
+    fn impact(x: f32, y: f32) -> Vec<(f32, f32)> {
+        [1.0, -0.6, 0.4, -0.2, 0.1].iter().map(|s| (s * x, s * y)).collect()
+    }
+
+    #[test]
+    fn motor_ramp_is_not_a_bump() {
+        // Speed up hard, cruise, and brake hard, with a little vibration on top; the acceleration
+        // gets well past the threshold but never changes quickly
+        let mut trace = calm(10);
+        for i in 0..40 {
+            let ramp = 0.04 * i.min(40 - i) as f32;
+            let vibration = if i % 2 == 0 { 0.05 } else { -0.05 };
+            trace.push((ramp + vibration, vibration));
+        }
+        trace.extend(calm(10));
+        trace.extend(trace.clone().iter().map(|&(x, y)| (-x, y)).collect::<Vec<_>>());
+        assert!(run(0, &trace).is_empty());
+    }
+
+    #[test]
+    fn front_impact() {
+        let mut trace = calm(10);
+        trace.extend(impact(-1.5, 0.2)); // running into something pushes the robot backwards
+        trace.extend(calm(10));
+
+        let events = run(1000, &trace);
+        assert_eq!(events.len(), 1);
+        let (index, event) = events[0];
+        assert_eq!(index, 10);
+        assert!(event.direction == BumpDirection::Front);
+        assert!(event.magnitude_g > 1.4);
+        assert_eq!(event.timestamp_ms, 1200);
+    }
+
+    #[test]
+    fn side_impacts() {
+        for &(y, direction) in &[(-1.2, BumpDirection::Left), (1.2, BumpDirection::Right)] {
+            let mut trace = calm(10);
+            trace.extend(impact(0.3, y));
+            trace.extend(calm(10));
+
+            let events = run(0, &trace);
+            assert_eq!(events.len(), 1);
+            assert!(events[0].1.direction == direction);
+        }
+    }
+
+    #[test]
+    fn holdoff() {
+        // The ringing after the first hit is big enough to count on its own, but falls inside the
+        // holdoff; a second hit after the holdoff is reported
+        let mut trace = calm(10);
+        trace.extend(impact(-2.0, 0.0));
+        trace.extend(calm(20)); // 500ms after the first hit
+        trace.extend(impact(0.0, 1.5));
+        trace.extend(calm(10));
+
+        let events = run(0, &trace);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].0, 10);
+        assert_eq!(events[1].0, 35);
+        assert!(events[1].1.direction == BumpDirection::Right);
+    }
+
+    #[test]
+    fn holdoff_across_clock_wrap() {
+        let mut trace = calm(10);
+        trace.extend(impact(-2.0, 0.0));
+        trace.extend(calm(20));
+        trace.extend(impact(-2.0, 0.0));
+
+        // Put the first hit just before the millisecond clock wraps
+        let events = run(u32::MAX - 10 * SAMPLE_MS, &trace);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].1.timestamp_ms, u32::MAX);
+        assert_eq!(events[1].0, 35);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{
+    avr_async::Waiter,
+    mem::Allocator,
+    params::{
+        self,
+        Param,
+        ParamKind,
+    },
+    uno::ir_sensors::PROFILE_NAMES,
+};
+use arduino_uno::{
+    hal::{
+        clock::MHz16,
+        port::mode::Floating,
+        usart::Usart0,
+    },
+    prelude::*,
+};
+use core::{
+    cell::RefCell,
+    future::Future,
+};
+use ufmt::{
+    uwrite,
+    uwriteln,
+};
+use void::ResultVoidExt;
+
+// The USART only buffers a couple of bytes, so this is fast enough for typing but not for pasting
+const POLL_DELAY_MS: u32 = 2;
+const MAX_LINE_LEN: usize = 32;
+
+type Serial = Usart0<MHz16, Floating>;
+
+// A line-based command interpreter on the serial port:
+//
+//     list                  print every parameter with its range
+//     get <name>            print one parameter
+//     set <name> <value>    change a parameter (saved at the start of the next run)
+//     reset <name>          go back to the default value
+//     profiles              list the calibration profiles
+//     profile <name>        select a calibration profile by name
+pub struct Console {
+    serial: RefCell<Serial>,
+}
+
+impl Console {
+    pub fn new(serial: Serial) -> &'static Console {
+        Allocator::get().new(Console {
+            serial: RefCell::new(serial),
+        })
+    }
+
+    pub fn get_console_driver(&'static self) -> &'static mut dyn Future<Output = !> {
+        let future = async move || {
+            let mut line = [0u8; MAX_LINE_LEN];
+            let mut len = 0;
+            loop {
+                let byte = self.serial.borrow_mut().read();
+                match byte {
+                    Ok(b'\r') | Ok(b'\n') => {
+                        if len > 0 {
+                            self.handle_line(&line[..len]);
+                        }
+                        len = 0;
+                    },
+                    Ok(b) if len < MAX_LINE_LEN => {
+                        line[len] = b;
+                        len += 1;
+                    },
+                    Ok(_) => (), // drop anything past the end of the line buffer
+                    Err(_) => Waiter::new(POLL_DELAY_MS).await,
+                }
+            }
+        };
+        Allocator::get().new(future())
+    }
+
+    // For reporting results (calibration quality, etc) to whoever's listening
+    pub fn write_line(&self, message: &str) {
+        uwriteln!(&mut *self.serial.borrow_mut(), "{}\r", message).void_unwrap();
+    }
+
+    pub fn write_pass_fail(&self, name: &str, passed: bool) {
+        let result = if passed { "pass" } else { "fail" };
+        uwriteln!(&mut *self.serial.borrow_mut(), "{}: {}\r", name, result).void_unwrap();
+    }
+
+    pub fn write_value(&self, name: &str, value: f32) {
+        let serial = &mut *self.serial.borrow_mut();
+        uwrite!(serial, "{} = ", name).void_unwrap();
+        write_f32(serial, value, ParamKind::Float);
+        uwriteln!(serial, "\r").void_unwrap();
+    }
+
+    fn handle_line(&self, line: &[u8]) {
+        let mut words = line.split(|&b| b == b' ').filter(|w| !w.is_empty());
+        let command = words.next().unwrap_or(&[]);
+        let arg = words.next();
+        let param = arg.map(Param::find);
+        let value = words.next().and_then(parse_f32);
+
+        let serial = &mut *self.serial.borrow_mut();
+        match (command, param, value) {
+            (b"profiles", None, None) => {
+                let active = params::get_u32(Param::Profile) as usize;
+                for (i, name) in PROFILE_NAMES.iter().enumerate() {
+                    let marker = if i == active { "*" } else { " " };
+                    uwriteln!(serial, "{} {} {}\r", marker, i, *name).void_unwrap();
+                }
+            },
+            (b"profile", _, None) => match arg.and_then(|a| PROFILE_NAMES.iter().position(|n| n.as_bytes() == a)) {
+                Some(i) => {
+                    params::set(Param::Profile, i as f32).ok();
+                    write_param(serial, Param::Profile);
+                },
+                None => uwriteln!(serial, "unknown profile\r").void_unwrap(),
+            },
+            (b"list", None, None) => {
+                for &p in Param::all() {
+                    write_param(serial, p);
+                }
+            },
+            (b"get", Some(Some(p)), None) => write_param(serial, p),
+            (b"set", Some(Some(p)), Some(v)) => match params::set(p, v) {
+                Ok(()) => write_param(serial, p),
+                Err(_) => uwriteln!(serial, "out of range\r").void_unwrap(),
+            },
+            (b"reset", Some(Some(p)), None) => {
+                params::reset(p);
+                write_param(serial, p);
+            },
+            (_, Some(None), _) => uwriteln!(serial, "unknown parameter\r").void_unwrap(),
+            _ => uwriteln!(serial, "?\r").void_unwrap(),
+        }
+    }
+}
+
+fn write_param(serial: &mut Serial, param: Param) {
+    let def = param.def();
+    uwrite!(serial, "{} = ", def.name).void_unwrap();
+    write_f32(serial, params::get(param), def.kind);
+    uwrite!(serial, " [").void_unwrap();
+    write_f32(serial, def.min, def.kind);
+    uwrite!(serial, ", ").void_unwrap();
+    write_f32(serial, def.max, def.kind);
+    uwriteln!(serial, "]\r").void_unwrap();
+}
+
+// ufmt can't print floats, so we do it by hand with three decimal places
+fn write_f32(serial: &mut Serial, value: f32, kind: ParamKind) {
+    if value < 0.0 {
+        uwrite!(serial, "-").void_unwrap();
+    }
+    let value = if value < 0.0 { -value } else { value };
+    if kind == ParamKind::Int {
+        uwrite!(serial, "{}", value as u32).void_unwrap();
+    } else {
+        let thousandths = (value * 1000.0 + 0.5) as u32;
+        let frac_part = thousandths % 1000;
+        uwrite!(serial, "{}.", thousandths / 1000).void_unwrap();
+        if frac_part < 100 {
+            uwrite!(serial, "0").void_unwrap();
+        }
+        if frac_part < 10 {
+            uwrite!(serial, "0").void_unwrap();
+        }
+        uwrite!(serial, "{}", frac_part).void_unwrap();
+    }
+}
+
+// Parses things like "12", "-0.25", or ".5"; pulling in core's float parser costs several KB of
+// flash that we don't have
+fn parse_f32(word: &[u8]) -> Option<f32> {
+    let (negative, digits) = match word.split_first() {
+        Some((&b'-', rest)) => (true, rest),
+        _ => (false, word),
+    };
+
+    let (mut value, mut scale, mut seen_point, mut seen_digit) = (0.0f32, 1.0f32, false, false);
+    for &b in digits {
+        match b {
+            b'0'..=b'9' if seen_point => {
+                scale /= 10.0;
+                value += (b - b'0') as f32 * scale;
+                seen_digit = true;
+            },
+            b'0'..=b'9' => {
+                value = value * 10.0 + (b - b'0') as f32;
+                seen_digit = true;
+            },
+            b'.' if !seen_point => seen_point = true,
+            _ => return None,
+        }
+    }
+
+    if !seen_digit {
+        return None;
+    }
+    Some(if negative { -value } else { value })
+}
@@ -0,0 +1,222 @@
+// Edge detection on top of the calibrated IR readings.  Each sensor switches on when it reads
+// above its threshold and only switches back off once it drops a hysteresis margin below it, so a
+// sensor sitting right on the boundary doesn't flicker.  The sensors are then grouped into sides,
+// and a change of side has to hold for a few reads in a row before it's reported.
+//
+// IRSensors builds the EdgeConfig from the parameters and feeds the EdgeDetector after every
+// calibrated read; nothing in here depends on either, so it can be fed recorded traces on the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    LeftEdge,
+    RightEdge,
+    FrontEdge, // also used when both sides see the edge at once
+    Cleared,
+}
+
+#[derive(Clone, Copy)]
+pub struct EdgeEvent {
+    pub kind: EdgeKind,
+    pub timestamp_ms: u32,
+}
+
+pub struct EdgeConfig<const N: usize> {
+    pub thresholds: [u16; N],
+    pub hysteresis: u16,
+    pub debounce_reads: u8,
+}
+
+pub struct EdgeDetector<const N: usize> {
+    on_edge: [bool; N],
+    current: EdgeKind,
+    candidate: EdgeKind,
+    candidate_reads: u8,
+}
+
+impl<const N: usize> EdgeDetector<N> {
+    pub fn new() -> EdgeDetector<N> {
+        EdgeDetector {
+            on_edge: [false; N],
+            current: EdgeKind::Cleared,
+            candidate: EdgeKind::Cleared,
+            candidate_reads: 0,
+        }
+    }
+
+    // The debounced state, as of the last update
+    pub fn current(&self) -> EdgeKind {
+        self.current
+    }
+
+    // Returns an event whenever the debounced state changes
+    pub fn update(&mut self, timestamp_ms: u32, values: &[u16; N], config: &EdgeConfig<N>) -> Option<EdgeEvent> {
+        for (i, &value) in values.iter().enumerate() {
+            let threshold = config.thresholds[i];
+            // With the margin as big as the threshold a sensor could never switch back off, so
+            // keep it to at most half
+            let hysteresis = config.hysteresis.min(threshold / 2);
+            if value > threshold {
+                self.on_edge[i] = true;
+            } else if value < threshold - hysteresis {
+                self.on_edge[i] = false;
+            }
+        }
+
+        let kind = self.classify();
+        if kind != self.candidate {
+            self.candidate = kind;
+            self.candidate_reads = 0;
+        }
+        self.candidate_reads = self.candidate_reads.saturating_add(1);
+
+        if self.candidate == self.current || self.candidate_reads < config.debounce_reads {
+            return None;
+        }
+        self.current = self.candidate;
+        Some(EdgeEvent {
+            kind: self.current,
+            timestamp_ms,
+        })
+    }
+
+    // Sensor 0 is on the robot's left; the outer third of the array on either side counts as that
+    // side, and everything in between as the front
+    fn classify(&self) -> EdgeKind {
+        let side = N / 3;
+        let any = |sensors: &[bool]| sensors.iter().any(|&on| on);
+        let (left, rest) = self.on_edge.split_at(side);
+        let (front, right) = rest.split_at(N - 2 * side);
+        match (any(left), any(front), any(right)) {
+            (false, false, false) => EdgeKind::Cleared,
+            (true, false, false) => EdgeKind::LeftEdge,
+            (false, false, true) => EdgeKind::RightEdge,
+            _ => EdgeKind::FrontEdge,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: u16 = 500;
+    const ON: u16 = 800;
+
+    fn config<const N: usize>(hysteresis: u16, debounce_reads: u8) -> EdgeConfig<N> {
+        EdgeConfig {
+            thresholds: [THRESHOLD; N],
+            hysteresis,
+            debounce_reads,
+        }
+    }
+
+    // Feeds each read in turn, returning the event kinds along with the index of the read that
+    // raised them
+    fn run<const N: usize>(config: &EdgeConfig<N>, reads: &[[u16; N]]) -> Vec<(usize, EdgeKind)> {
+        let mut detector = EdgeDetector::new();
+        let mut events = Vec::new();
+        for (i, values) in reads.iter().enumerate() {
+            if let Some(event) = detector.update(10 * i as u32, values, config) {
+                assert_eq!(event.timestamp_ms, 10 * i as u32);
+                events.push((i, event.kind));
+            }
+        }
+        events
+    }
+
+    // What each sensor reports on its own, and then the two outermost sensors together
+    fn classify_each<const N: usize>() -> Vec<EdgeKind> {
+        let config = config::<N>(100, 1);
+        let mut kinds = Vec::new();
+        for sensor in 0..N {
+            let mut values = [0; N];
+            values[sensor] = ON;
+            let mut detector = EdgeDetector::new();
+            detector.update(0, &values, &config);
+            kinds.push(detector.current());
+        }
+
+        let mut values = [0; N];
+        values[0] = ON;
+        values[N - 1] = ON;
+        let mut detector = EdgeDetector::new();
+        detector.update(0, &values, &config);
+        kinds.push(detector.current());
+        kinds
+    }
+
+    #[test]
+    fn sides_for_each_array_size() {
+        use EdgeKind::*;
+        assert_eq!(classify_each::<3>(), vec![LeftEdge, FrontEdge, RightEdge, FrontEdge]);
+        assert_eq!(
+            classify_each::<5>(),
+            vec![LeftEdge, FrontEdge, FrontEdge, FrontEdge, RightEdge, FrontEdge]
+        );
+        assert_eq!(
+            classify_each::<6>(),
+            vec![LeftEdge, LeftEdge, FrontEdge, FrontEdge, RightEdge, RightEdge, FrontEdge]
+        );
+        assert_eq!(
+            classify_each::<8>(),
+            vec![LeftEdge, LeftEdge, FrontEdge, FrontEdge, FrontEdge, FrontEdge, RightEdge, RightEdge, FrontEdge]
+        );
+    }
+
+    #[test]
+    fn hysteresis_holds_a_sensor_on_near_the_threshold() {
+        let config = config::<3>(100, 1);
+        let reads = [
+            [0, 0, 0],
+            [600, 0, 0],
+            [450, 0, 0],
+            [510, 0, 0],
+            [401, 0, 0],
+            [399, 0, 0],
+            [450, 0, 0],
+        ];
+        assert_eq!(
+            run(&config, &reads),
+            vec![(1, EdgeKind::LeftEdge), (5, EdgeKind::Cleared)]
+        );
+    }
+
+    #[test]
+    fn hysteresis_is_capped_at_half_the_threshold() {
+        // A margin as big as the threshold would leave the sensor stuck on forever
+        let config = config::<3>(THRESHOLD, 1);
+        let reads = [[0, 0, 600], [0, 0, 300], [0, 0, 249]];
+        assert_eq!(
+            run(&config, &reads),
+            vec![(0, EdgeKind::RightEdge), (2, EdgeKind::Cleared)]
+        );
+    }
+
+    #[test]
+    fn debounce_needs_consecutive_reads() {
+        let config = config::<6>(100, 3);
+        let left = [ON, 0, 0, 0, 0, 0];
+        let clear = [0; 6];
+        let reads = [left, left, clear, left, left, left, left, clear, clear, clear];
+        assert_eq!(
+            run(&config, &reads),
+            vec![(5, EdgeKind::LeftEdge), (9, EdgeKind::Cleared)]
+        );
+    }
+
+    #[test]
+    fn switching_sides_is_debounced_too() {
+        let config = config::<8>(100, 2);
+        let left = [ON, 0, 0, 0, 0, 0, 0, 0];
+        let right = [0, 0, 0, 0, 0, 0, 0, ON];
+        let front = [0, 0, 0, ON, ON, 0, 0, 0];
+        let reads = [left, left, right, front, front, right, right];
+        assert_eq!(
+            run(&config, &reads),
+            vec![
+                (1, EdgeKind::LeftEdge),
+                (4, EdgeKind::FrontEdge),
+                (6, EdgeKind::RightEdge)
+            ]
+        );
+    }
+}
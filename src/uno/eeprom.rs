@@ -1,79 +1,516 @@
 use crate::{
-    Uno,
-    Waiter,
+    avr_async::NTASKS,
+    mem::Allocator,
+    uno::{
+        ir_sensors::{
+            IRCalibrationVector,
+            MAX_IR_SENSORS,
+            PROFILE_COUNT,
+        },
+        kv_store::{
+            KvStorage,
+            KV_REGION_SIZE,
+        },
+        mag_calibration::MagCalibration,
+        IR_SENSOR_COUNT,
+    },
+    util::*,
 };
 use arduino_uno::pac::EEPROM;
-use avr_hal_generic::avr_device;
-use core::ops::Add;
-
-// The IMU and IR calibration values take two bytes each
-pub const IMU_X_MIN_ADDR: u8 = 0;
-pub const IMU_X_MAX_ADDR: u8 = 2;
-pub const IMU_Y_MIN_ADDR: u8 = 4;
-pub const IMU_Y_MAX_ADDR: u8 = 6;
-pub const IR_0_MIN_ADDR: u8 = 8;
-pub const IR_0_MAX_ADDR: u8 = 10;
-pub const IR_1_MIN_ADDR: u8 = 12;
-pub const IR_1_MAX_ADDR: u8 = 14;
-pub const IR_2_MIN_ADDR: u8 = 16;
-pub const IR_2_MAX_ADDR: u8 = 18;
-pub const IR_3_MIN_ADDR: u8 = 20;
-pub const IR_3_MAX_ADDR: u8 = 22;
-pub const IR_4_MIN_ADDR: u8 = 24;
-pub const IR_4_MAX_ADDR: u8 = 26;
-pub const IR_5_MIN_ADDR: u8 = 28;
-pub const IR_5_MAX_ADDR: u8 = 30;
-pub const _END_ADDR: u8 = 32;
-
-impl Uno {
-    pub async fn read_eeprom_u8(&mut self, addr: u8) -> u8 {
-        while self.eeprom.eecr.read().eepe().bit_is_set() {
-            Waiter::new(1).await;
-        }
-
-        self.eeprom.eear.write(|w| unsafe { w.bits((addr as u8).into()) });
-        self.eeprom.eecr.write(|w| w.eere().set_bit());
-        self.eeprom.eedr.read().bits()
-    }
-
-    pub async fn read_eeprom_u16(&mut self, addr: u8) -> u16 {
-        let mut value: u16 = self.read_eeprom_u8(addr).await as u16;
-        value |= (self.read_eeprom_u8(addr + 1).await as u16) << 8;
-        value
-    }
-
-    pub async fn read_eeprom_u32(&mut self, addr: u8) -> u32 {
-        let mut value: u32 = self.read_eeprom_u8(addr).await as u32;
-        value |= (self.read_eeprom_u8(addr + 1).await as u32) << 8;
-        value |= (self.read_eeprom_u8(addr + 2).await as u32) << 16;
-        value |= (self.read_eeprom_u8(addr + 3).await as u32) << 24;
-        value
-    }
-
-    pub async fn write_eeprom_u8(&mut self, addr: u8, value: u8) {
-        while self.eeprom.eecr.read().eepe().bit_is_set() {
-            Waiter::new(1).await;
-        }
-
-        avr_device::interrupt::free(|_| {
-            self.eeprom.eear.write(|w| unsafe { w.bits(addr.into()) });
-            self.eeprom.eedr.write(|w| unsafe { w.bits(value) });
+use avr_hal_generic::{
+    avr_device,
+    avr_device::interrupt::free as critical_section,
+};
+use core::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    ptr::{
+        read_volatile,
+        write_volatile,
+    },
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+};
+
+pub use crate::uno::eeprom_value::EepromValue;
+
+pub const EEPROM_SIZE: usize = 1024;
+
+// Values are serialized into a scratch buffer on the stack before being written, so this bounds
+// the size of any single value we can store
+pub const MAX_VALUE_SIZE: usize = 64;
+
+// Each byte takes ~3.4ms to program, so this is about 50ms worth of writes
+const WRITE_QUEUE_LEN: usize = 16;
+
+// The commit marker is set to DIRTY before the first byte of a batch is programmed and back to
+// CLEAN once the whole batch has landed; if we lose power partway through, it stays DIRTY
+const COMMIT_DIRTY: u8 = 0x00;
+const COMMIT_CLEAN: u8 = 0xa5;
+
+// The EEPROM driver and the reads from the states both wait on EE_READY, so each needs its own
+// slot or one of them would never get woken
+static mut EE_READY_DRIVER_WAKER: Option<Waker> = None;
+static mut EE_READY_READER_WAKER: Option<Waker> = None;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EepromError {
+    OutOfBounds,
+    ValueTooLarge,
+}
+
+// A typed location in the EEPROM; these should be declared with `eeprom_layout!` so that the
+// compiler can check that they don't overlap
+pub struct EepromAddr<T> {
+    pub addr: u16,
+    _value: PhantomData<T>,
+}
+
+impl<T> EepromAddr<T> {
+    pub const fn new(addr: u16) -> EepromAddr<T> {
+        EepromAddr {
+            addr,
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T: EepromValue, const N: usize> EepromAddr<[T; N]> {
+    // The address of the i'th element of an array field
+    pub fn at(self, i: usize) -> Option<EepromAddr<T>> {
+        if i < N {
+            Some(EepromAddr::new(self.addr + (i * T::SIZE) as u16))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Clone for EepromAddr<T> {
+    fn clone(&self) -> Self {
+        EepromAddr::new(self.addr)
+    }
+}
+
+impl<T> Copy for EepromAddr<T> {}
+
+// Declare a set of typed EEPROM fields.  Fields must be listed in increasing address order; if any
+// field runs into the next one (or off the end of the EEPROM), the `[(); 0] = [(); 1]` assignment
+// below fails to typecheck and the build breaks.
+macro_rules! eeprom_layout {
+    ($($name:ident: $t:ty = $addr:expr;)+) => {
+        $(pub const $name: EepromAddr<$t> = EepromAddr::new($addr);)+
+        eeprom_layout!(@check $(($addr, $t)),+);
+    };
+    (@check ($addr:expr, $t:ty), ($next:expr, $next_t:ty) $(, $rest:tt)*) => {
+        const _: [(); 0] = [(); ($addr as usize + <$t as EepromValue>::SIZE > $next as usize) as usize];
+        eeprom_layout!(@check ($next, $next_t) $(, $rest)*);
+    };
+    (@check ($addr:expr, $t:ty)) => {
+        const _: [(); 0] = [(); ($addr as usize + <$t as EepromValue>::SIZE > EEPROM_SIZE) as usize];
+    };
+}
+
+// Bytes 0-7 held the old min/max magnetometer calibration, and bytes 8-103 the IR profiles before
+// they moved to the end so that they could grow.  The IR profiles started at 140 until the
+// magnetometer calibration picked up its z reading; boards calibrated before that need to be
+// recalibrated.
+eeprom_layout! {
+    GYRO_BIAS: (f32, f32, f32) = 104; // dps
+    MAG_CALIBRATION: MagCalibration = 116;
+    IR_PROFILES: [IRCalibrationVector<MAX_IR_SENSORS>; PROFILE_COUNT] = 144; // one calibration per surface
+    KV_REGION: [u8; KV_REGION_SIZE] = 272;
+    COMMIT_MARKER: u8 = 1023;
+}
+
+// Every IR profile gets a slot with room for MAX_IR_SENSORS, and the sensors we have use the front
+// of it, so changing the sensor count doesn't move the other profiles (or the KV store)
+const _: [(); 0] = [(); (IR_SENSOR_COUNT > MAX_IR_SENSORS) as usize];
+
+pub fn ir_profile(i: usize) -> Option<EepromAddr<IRCalibrationVector<IR_SENSOR_COUNT>>> {
+    IR_PROFILES.at(i).map(|slot| EepromAddr::new(slot.addr))
+}
+
+fn check_bounds(addr: u16, len: usize) -> Result<(), EepromError> {
+    if addr as usize + len > EEPROM_SIZE {
+        return Err(EepromError::OutOfBounds);
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+struct PendingWrite {
+    addr: u16,
+    value: u8,
+    // Journaled writes are bracketed by the commit marker; callers that handle power failures
+    // themselves (e.g., the KV store) skip it to avoid wearing out the marker cell
+    journaled: bool,
+}
+
+struct WriteQueue {
+    entries: [PendingWrite; WRITE_QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl WriteQueue {
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == WRITE_QUEUE_LEN
+    }
+
+    // If the most recent pending write is to the same address we just overwrite its value, so
+    // repeated writes to the same location only cost one erase/write cycle.  We don't coalesce
+    // with anything further back in the queue, because that would reorder the writes.
+    fn push(&mut self, write: PendingWrite) -> bool {
+        if !self.is_empty() {
+            let tail = &mut self.entries[(self.head + self.len - 1) % WRITE_QUEUE_LEN];
+            if tail.addr == write.addr {
+                tail.value = write.value;
+                tail.journaled |= write.journaled;
+                return true;
+            }
+        }
+
+        if self.is_full() {
+            return false;
+        }
+        self.entries[(self.head + self.len) % WRITE_QUEUE_LEN] = write;
+        self.len += 1;
+        true
+    }
+
+    fn peek(&self) -> Option<PendingWrite> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.entries[self.head])
+        }
+    }
+
+    fn pop(&mut self) {
+        if !self.is_empty() {
+            self.head = (self.head + 1) % WRITE_QUEUE_LEN;
+            self.len -= 1;
+        }
+    }
+
+    // Returns the most recently queued value for addr
+    fn get(&self, addr: u16) -> Option<u8> {
+        (0..self.len)
+            .rev()
+            .map(|i| self.entries[(self.head + i) % WRITE_QUEUE_LEN])
+            .find(|e| e.addr == addr)
+            .map(|e| e.value)
+    }
+}
+
+pub struct Eeprom {
+    regs: EEPROM,
+    queue: RefCell<WriteQueue>,
+    idle: Cell<bool>,
+    driver_waker: RefCell<[Option<Waker>; 1]>, // only the driver waits for writes to be queued
+    writer_wakers: RefCell<[Option<Waker>; NTASKS]>, // any task can be waiting to write or flush
+}
+
+impl Eeprom {
+    // Like the MotorController, this is a static reference because the driver holds onto "self"
+    pub fn new(regs: EEPROM) -> &'static Eeprom {
+        Allocator::get().new(Eeprom {
+            regs,
+            queue: RefCell::new(WriteQueue {
+                entries: [PendingWrite {
+                    addr: 0,
+                    value: 0,
+                    journaled: false,
+                }; WRITE_QUEUE_LEN],
+                head: 0,
+                len: 0,
+            }),
+            idle: Cell::new(true),
+            driver_waker: RefCell::new([None]),
+            writer_wakers: RefCell::new(Default::default()),
+        })
+    }
+
+    pub async fn read<T: EepromValue>(&self, addr: EepromAddr<T>) -> Result<T, EepromError> {
+        if T::SIZE > MAX_VALUE_SIZE {
+            return Err(EepromError::ValueTooLarge);
+        }
+
+        let mut buf = [0u8; MAX_VALUE_SIZE];
+        self.read_bytes(addr.addr, &mut buf[..T::SIZE]).await?;
+        Ok(T::load(&buf))
+    }
+
+    // Writes are queued up and handled by the EEPROM driver in the background; call `flush` to
+    // wait until they've actually been committed
+    pub async fn write<T: EepromValue>(&self, addr: EepromAddr<T>, value: &T) -> Result<(), EepromError> {
+        if T::SIZE > MAX_VALUE_SIZE {
+            return Err(EepromError::ValueTooLarge);
+        }
+
+        let mut buf = [0u8; MAX_VALUE_SIZE];
+        value.store(&mut buf);
+        self.write_bytes(addr.addr, &buf[..T::SIZE]).await
+    }
+
+    pub async fn read_bytes(&self, addr: u16, buf: &mut [u8]) -> Result<(), EepromError> {
+        check_bounds(addr, buf.len())?;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.read_u8(addr + i as u16).await;
+        }
+        Ok(())
+    }
+
+    pub async fn write_bytes(&self, addr: u16, buf: &[u8]) -> Result<(), EepromError> {
+        check_bounds(addr, buf.len())?;
+        for (i, &b) in buf.iter().enumerate() {
+            let a = addr + i as u16;
+            WaitFor::new(&self.writer_wakers, || !self.queue.borrow().is_full()).await;
+            self.queue.borrow_mut().push(PendingWrite {
+                addr: a,
+                value: b,
+                journaled: true,
+            });
+            wake(&self.driver_waker);
+        }
+        Ok(())
+    }
+
+    // Non-blocking single-byte access for code that manages its own futures (see KvStorage).
+    // Writes made this way aren't covered by the commit marker.
+    pub fn poll_read_u8(&self, addr: u16, ctx: &mut Context) -> Poll<u8> {
+        if let Some(value) = self.queue.borrow().get(addr) {
+            return Poll::Ready(value);
+        }
+
+        let mut ready = EepromReady {
+            regs: &self.regs,
+            waiter: ReadyWaiter::Reader,
+        };
+        match Pin::new(&mut ready).poll(ctx) {
+            Poll::Ready(()) => Poll::Ready(self.read_u8_now(addr)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    pub fn poll_write_u8(&self, addr: u16, value: u8, ctx: &mut Context) -> Poll<()> {
+        let pushed = self.queue.borrow_mut().push(PendingWrite {
+            addr,
+            value,
+            journaled: false,
+        });
+        if !pushed {
+            register(&mut *self.writer_wakers.borrow_mut(), ctx.waker());
+            return Poll::Pending;
+        }
+        wake(&self.driver_waker);
+        Poll::Ready(())
+    }
+
+    pub async fn flush(&self) {
+        WaitFor::new(&self.writer_wakers, || {
+            self.idle.get() && self.queue.borrow().is_empty()
+        })
+        .await;
+    }
+
+    // Returns false if the last batch of writes was interrupted (or nothing has ever been
+    // written), in which case the stored data shouldn't be trusted
+    pub async fn is_consistent(&self) -> bool {
+        self.read_u8(COMMIT_MARKER.addr).await == COMMIT_CLEAN
+    }
+
+    pub fn get_eeprom_driver(&'static self) -> &'static mut dyn Future<Output = !> {
+        let future = async move || loop {
+            WaitFor::new(&self.driver_waker, || !self.queue.borrow().is_empty()).await;
+            self.idle.set(false);
+
+            let mut marked_dirty = false;
+            loop {
+                EepromReady {
+                    regs: &self.regs,
+                    waiter: ReadyWaiter::Driver,
+                }
+                .await;
+
+                // Peek rather than pop, so that reads of this address still see the pending value
+                // until the hardware actually has it
+                let write = match self.queue.borrow().peek() {
+                    Some(write) => write,
+                    None => break,
+                };
+                // Each EEPROM cell is only good for ~100k erase/write cycles, so we skip any
+                // bytes that already hold the value we want
+                if self.read_u8_now(write.addr) != write.value {
+                    if write.journaled && !marked_dirty {
+                        // The pending value may change while we wait for the marker to land, so
+                        // go back around and look at the queue again
+                        self.program_u8(COMMIT_MARKER.addr, COMMIT_DIRTY);
+                        marked_dirty = true;
+                        continue;
+                    }
+                    self.program_u8(write.addr, write.value);
+                }
+                self.queue.borrow_mut().pop();
+                wake(&self.writer_wakers);
+            }
+
+            if marked_dirty {
+                self.program_u8(COMMIT_MARKER.addr, COMMIT_CLEAN);
+                EepromReady {
+                    regs: &self.regs,
+                    waiter: ReadyWaiter::Driver,
+                }
+                .await;
+            }
+            self.idle.set(true);
+            wake(&self.writer_wakers);
+        };
+        Allocator::get().new(future())
+    }
+
+    async fn read_u8(&self, addr: u16) -> u8 {
+        if let Some(value) = self.queue.borrow().get(addr) {
+            return value;
+        }
+
+        EepromReady {
+            regs: &self.regs,
+            waiter: ReadyWaiter::Reader,
+        }
+        .await;
+        self.read_u8_now(addr)
+    }
+
+    // Only call these when the EEPROM isn't busy (i.e., after EepromReady has completed)
+    fn read_u8_now(&self, addr: u16) -> u8 {
+        self.regs.eear.write(|w| unsafe { w.bits(addr) });
+        self.regs.eecr.write(|w| w.eere().set_bit());
+        self.regs.eedr.read().bits()
+    }
+
+    fn program_u8(&self, addr: u16, value: u8) {
+        critical_section(|_| {
+            self.regs.eear.write(|w| unsafe { w.bits(addr) });
+            self.regs.eedr.write(|w| unsafe { w.bits(value) });
 
             // The master write-enable and the write-enable have to be separate instructions
-            self.eeprom.eecr.write(|w| w.eempe().set_bit());
-            self.eeprom.eecr.write(|w| w.eepe().set_bit());
+            self.regs.eecr.write(|w| w.eempe().set_bit());
+            self.regs.eecr.write(|w| w.eepe().set_bit());
         });
     }
+}
+
+impl KvStorage for Eeprom {
+    fn poll_read_u8(&self, addr: u16, ctx: &mut Context) -> Poll<u8> {
+        Eeprom::poll_read_u8(self, addr, ctx)
+    }
 
-    pub async fn write_eeprom_u16(&mut self, addr: u8, value: u16) {
-        self.write_eeprom_u8(addr, value as u8).await;
-        self.write_eeprom_u8(addr + 1, (value >> 8) as u8).await;
+    fn poll_write_u8(&self, addr: u16, value: u8, ctx: &mut Context) -> Poll<()> {
+        Eeprom::poll_write_u8(self, addr, value, ctx)
     }
+}
+
+// A task that's polled again while it waits keeps its slot, so one slot per task is always enough;
+// if they're somehow all taken, we'd rather spin than sleep forever
+fn register(slots: &mut [Option<Waker>], waker: &Waker) {
+    let existing = slots
+        .iter()
+        .position(|slot| matches!(slot, Some(w) if w.will_wake(waker)));
+    match existing.or_else(|| slots.iter().position(|slot| slot.is_none())) {
+        Some(i) => slots[i] = Some(waker.clone()),
+        None => waker.clone().wake(),
+    }
+}
+
+fn wake(slots: &RefCell<[Option<Waker>]>) {
+    for slot in slots.borrow_mut().iter_mut() {
+        if let Some(waker) = slot.take() {
+            waker.wake();
+        }
+    }
+}
+
+// Resolves once `until` returns true; whoever changes the condition is responsible for waking
+// the wakers stored in `slots`
+struct WaitFor<'a, F: Fn() -> bool> {
+    slots: &'a RefCell<[Option<Waker>]>,
+    until: F,
+}
+
+impl<'a, F: Fn() -> bool> WaitFor<'a, F> {
+    fn new(slots: &'a RefCell<[Option<Waker>]>, until: F) -> WaitFor<'a, F> {
+        WaitFor { slots, until }
+    }
+}
+
+impl<'a, F: Fn() -> bool> Future for WaitFor<'a, F> {
+    type Output = ();
 
-    pub async fn write_eeprom_u32(&mut self, addr: u8, value: u16) {
-        self.write_eeprom_u8(addr, value as u8).await;
-        self.write_eeprom_u8(addr + 1, (value >> 8) as u8).await;
-        self.write_eeprom_u8(addr + 2, (value >> 16) as u8).await;
-        self.write_eeprom_u8(addr + 3, (value >> 23) as u8).await;
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        if (self.until)() {
+            return Poll::Ready(());
+        }
+        register(&mut *self.slots.borrow_mut(), ctx.waker());
+        Poll::Pending
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ReadyWaiter {
+    Driver,
+    Reader,
+}
+
+// Resolves once the EEPROM has finished any in-progress write
+struct EepromReady<'a> {
+    regs: &'a EEPROM,
+    waiter: ReadyWaiter,
+}
+
+impl<'a> Future for EepromReady<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        if self.regs.eecr.read().eepe().bit_is_clear() {
+            return Poll::Ready(());
+        }
+
+        // EE_READY fires continuously as long as EEPE is clear, so even if the write finishes
+        // before we get the interrupt enabled, we'll still get woken up
+        critical_section(|_| unsafe {
+            let slot = match self.waiter {
+                ReadyWaiter::Driver => &mut EE_READY_DRIVER_WAKER,
+                ReadyWaiter::Reader => &mut EE_READY_READER_WAKER,
+            };
+            *slot = Some(ctx.waker().clone());
+            write_volatile(EECR, read_volatile(EECR) | EERIE);
+        });
+        Poll::Pending
+    }
+}
+
+#[avr_device::interrupt(atmega328p)]
+unsafe fn EE_READY() {
+    // Turn the interrupt back off or it will keep firing forever
+    write_volatile(EECR, read_volatile(EECR) & !EERIE);
+    if let Some(waker) = EE_READY_DRIVER_WAKER.take() {
+        waker.wake();
+    }
+    if let Some(waker) = EE_READY_READER_WAKER.take() {
+        waker.wake();
     }
 }
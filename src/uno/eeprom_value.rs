@@ -0,0 +1,114 @@
+// How values are laid out in the EEPROM (and in the KV store on top of it).  This is kept apart
+// from the EEPROM driver since none of it touches the hardware.
+
+// Anything with a fixed-size, little-endian byte layout can be stored in the EEPROM.  `store` and
+// `load` are handed a slice that is at least SIZE bytes long.
+pub trait EepromValue: Sized {
+    const SIZE: usize;
+
+    fn store(&self, buf: &mut [u8]);
+    fn load(buf: &[u8]) -> Self;
+}
+
+macro_rules! impl_eeprom_value_for_primitive {
+    ($($t:ty),+) => {
+        $(impl EepromValue for $t {
+            const SIZE: usize = core::mem::size_of::<$t>();
+
+            fn store(&self, buf: &mut [u8]) {
+                buf[..Self::SIZE].copy_from_slice(&self.to_le_bytes());
+            }
+
+            fn load(buf: &[u8]) -> Self {
+                let mut bytes = [0u8; core::mem::size_of::<$t>()];
+                bytes.copy_from_slice(&buf[..Self::SIZE]);
+                <$t>::from_le_bytes(bytes)
+            }
+        })+
+    };
+}
+
+impl_eeprom_value_for_primitive!(u8, i8, u16, i16, u32, i32, f32);
+
+impl EepromValue for bool {
+    const SIZE: usize = 1;
+
+    fn store(&self, buf: &mut [u8]) {
+        buf[0] = *self as u8;
+    }
+
+    fn load(buf: &[u8]) -> Self {
+        buf[0] != 0
+    }
+}
+
+impl<T: EepromValue + Copy + Default, const N: usize> EepromValue for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn store(&self, buf: &mut [u8]) {
+        for (i, v) in self.iter().enumerate() {
+            v.store(&mut buf[i * T::SIZE..]);
+        }
+    }
+
+    fn load(buf: &[u8]) -> Self {
+        let mut values = [T::default(); N];
+        for (i, v) in values.iter_mut().enumerate() {
+            *v = T::load(&buf[i * T::SIZE..]);
+        }
+        values
+    }
+}
+
+macro_rules! impl_eeprom_value_for_tuple {
+    ($(($($t:ident: $i:tt),+)),+) => {
+        $(impl<$($t: EepromValue),+> EepromValue for ($($t,)+) {
+            const SIZE: usize = 0 $(+ $t::SIZE)+;
+
+            #[allow(unused_assignments)]
+            fn store(&self, buf: &mut [u8]) {
+                let mut pos = 0;
+                $(self.$i.store(&mut buf[pos..]); pos += $t::SIZE;)+
+            }
+
+            #[allow(unused_assignments)]
+            fn load(buf: &[u8]) -> Self {
+                let mut pos = 0;
+                ($({ let v = $t::load(&buf[pos..]); pos += $t::SIZE; v },)+)
+            }
+        })+
+    };
+}
+
+impl_eeprom_value_for_tuple!((A: 0, B: 1), (A: 0, B: 1, C: 2), (A: 0, B: 1, C: 2, D: 3));
+
+// Implement EepromValue for a plain struct by storing each of the listed fields in order, e.g.
+//
+//     eeprom_record!(Foo { a: u16, b: f32 });
+#[macro_export]
+macro_rules! eeprom_record {
+    ($name:ident { $($field:ident: $t:ty),+ $(,)? }) => {
+        impl $crate::uno::eeprom_value::EepromValue for $name {
+            const SIZE: usize = 0 $(+ <$t as $crate::uno::eeprom_value::EepromValue>::SIZE)+;
+
+            #[allow(unused_assignments)]
+            fn store(&self, buf: &mut [u8]) {
+                let mut pos = 0;
+                $(
+                    $crate::uno::eeprom_value::EepromValue::store(&self.$field, &mut buf[pos..]);
+                    pos += <$t as $crate::uno::eeprom_value::EepromValue>::SIZE;
+                )+
+            }
+
+            #[allow(unused_assignments)]
+            fn load(buf: &[u8]) -> Self {
+                let mut pos = 0;
+                $(
+                    let $field = <$t as $crate::uno::eeprom_value::EepromValue>::load(&buf[pos..]);
+                    pos += <$t as $crate::uno::eeprom_value::EepromValue>::SIZE;
+                )+
+                $name { $($field),+ }
+            }
+        }
+    };
+}
@@ -0,0 +1,356 @@
+use crate::{
+    avr_async::{
+        block_on,
+        Waiter,
+    },
+    mem::Allocator,
+    uno::{
+        timers,
+        twi::{
+            Twi,
+            TwiError,
+        },
+    },
+};
+use core::{
+    cell::Cell,
+    future::Future,
+};
+
+const GYRO_ADDR: u8 = 0b1101011; // both chips we support answer here
+const GYRO_WHO_AM_I: u8 = 0x0f;
+
+// MinIMU-9 v3 and older
+const L3GD20H_ID: u8 = 0xd7;
+const L3GD20H_CTRL1: u8 = 0x20;
+const L3GD20H_CTRL4: u8 = 0x23;
+const L3GD20H_LOW_ODR: u8 = 0x39;
+const L3GD20H_STATUS_REG: u8 = 0x27;
+const L3GD20H_REG_OUT: u8 = 0x28;
+
+// MinIMU-9 v5 and AltIMU-10 v5; the accelerometer half of the chip belongs to the IMU driver, which
+// also turns on register auto-increment
+const LSM6DS33_ID: u8 = 0x69;
+const LSM6DS33_CTRL2_G: u8 = 0x11;
+const LSM6DS33_STATUS_REG: u8 = 0x1e;
+const LSM6DS33_REG_OUT: u8 = 0x22;
+
+const BIAS_CALIBRATION_SAMPLES: u16 = 200;
+const REINIT_DELAY_MS: u32 = 1000;
+
+#[derive(Clone, Copy)]
+pub enum GyroError {
+    Bus(TwiError),
+    NotDetected, // nothing at the gyro's address identified as a chip we know about
+}
+
+impl From<TwiError> for GyroError {
+    fn from(e: TwiError) -> GyroError {
+        GyroError::Bus(e)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum GyroChip {
+    L3gd20h,
+    Lsm6ds33,
+}
+
+impl GyroChip {
+    fn from_id(id: u8) -> Option<GyroChip> {
+        match id {
+            L3GD20H_ID => Some(GyroChip::L3gd20h),
+            LSM6DS33_ID => Some(GyroChip::Lsm6ds33),
+            _ => None,
+        }
+    }
+
+    // The status register and its data-ready bit for the gyro
+    fn status(self) -> (u8, u8) {
+        match self {
+            GyroChip::L3gd20h => (L3GD20H_STATUS_REG, 0x08),
+            GyroChip::Lsm6ds33 => (LSM6DS33_STATUS_REG, 0x02),
+        }
+    }
+
+    // Includes the auto-increment bit if the chip needs one
+    fn out_reg(self) -> u8 {
+        match self {
+            GyroChip::L3gd20h => L3GD20H_REG_OUT | 0x80,
+            GyroChip::Lsm6ds33 => LSM6DS33_REG_OUT,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum GyroRate {
+    Hz100,
+    Hz200,
+    Hz400,
+    Hz800,
+}
+
+impl GyroRate {
+    // The DR bits in the L3GD20H's CTRL1; the LSM6DS33's nearest rates (104-833Hz) are these plus
+    // 0b0100
+    fn bits(self) -> u8 {
+        match self {
+            GyroRate::Hz100 => 0b00,
+            GyroRate::Hz200 => 0b01,
+            GyroRate::Hz400 => 0b10,
+            GyroRate::Hz800 => 0b11,
+        }
+    }
+
+    // Rounded up so we never poll faster than the gyro produces data
+    fn period_ms(self) -> u32 {
+        match self {
+            GyroRate::Hz100 => 10,
+            GyroRate::Hz200 => 5,
+            GyroRate::Hz400 => 3,
+            GyroRate::Hz800 => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum GyroRange {
+    Dps245,
+    Dps500,
+    Dps2000,
+}
+
+impl GyroRange {
+    // The FS bits in the L3GD20H's CTRL4 or the LSM6DS33's CTRL2_G
+    fn bits(self, chip: GyroChip) -> u8 {
+        match (self, chip) {
+            (GyroRange::Dps245, _) => 0b00,
+            (GyroRange::Dps500, _) => 0b01,
+            (GyroRange::Dps2000, GyroChip::L3gd20h) => 0b10,
+            (GyroRange::Dps2000, GyroChip::Lsm6ds33) => 0b11,
+        }
+    }
+
+    // Sensitivity in dps/LSB, from the L3GD20H datasheet (the LSM6DS33's are the same)
+    fn scale(self) -> f32 {
+        match self {
+            GyroRange::Dps245 => 0.00875,
+            GyroRange::Dps500 => 0.0175,
+            GyroRange::Dps2000 => 0.07,
+        }
+    }
+}
+
+// The gyro shares the I2C bus with the IMU; the driver integrates the z-axis rate into a yaw angle
+// in the background.  Yaw is counterclockwise-positive (looking down on the robot), which is the
+// opposite direction from compass heading.
+//
+// The gyro is either a standalone L3GD20H or the gyro half of an LSM6DS33; they share an address,
+// so we check which one answers before configuring it.
+//
+// If the gyro stops answering (e.g., a loose cable), the driver keeps trying to reconfigure it
+// about once a second; the chip may have lost power, so we can't assume its settings survived.
+pub struct Gyro {
+    twi: &'static Twi,
+    chip: Cell<Option<GyroChip>>,
+    rate: Cell<GyroRate>,
+    range: Cell<GyroRange>,
+    bias: Cell<(f32, f32, f32)>, // dps
+    yaw_degrees: Cell<f32>,
+    last_update_us: Cell<u32>,
+    configured: Cell<bool>,
+    healthy: Cell<bool>,
+    next_init_ms: Cell<u32>,
+}
+
+impl Gyro {
+    // Like the MotorController, this is a static reference because the driver holds onto "self"
+    pub fn new(twi: &'static Twi) -> &'static Gyro {
+        let gyro = Allocator::get().new(Gyro {
+            twi,
+            chip: Cell::new(None),
+            rate: Cell::new(GyroRate::Hz200),
+            range: Cell::new(GyroRange::Dps245),
+            bias: Cell::new((0.0, 0.0, 0.0)),
+            yaw_degrees: Cell::new(0.0),
+            last_update_us: Cell::new(0),
+            configured: Cell::new(false),
+            healthy: Cell::new(false),
+            next_init_ms: Cell::new(0),
+        });
+
+        // This runs before the executor starts, so we have to drive the bus by hand; if it fails,
+        // the driver will try again later
+        block_on(gyro.init()).ok();
+        gyro
+    }
+
+    pub async fn init(&self) -> Result<(), GyroError> {
+        let mut id: [u8; 1] = [0];
+        self.twi.write_read(GYRO_ADDR, &[GYRO_WHO_AM_I], &mut id).await?;
+        let chip = GyroChip::from_id(id[0]).ok_or(GyroError::NotDetected)?;
+        self.chip.set(Some(chip));
+
+        if chip == GyroChip::L3gd20h {
+            // 0x00 -> LOW_ODR off, so the DR bits select 100-800Hz
+            self.write_reg(L3GD20H_LOW_ODR, 0x00).await?;
+        }
+        self.set_range(self.range.get()).await?;
+        self.set_rate(self.rate.get()).await?;
+        self.configured.set(true);
+        Ok(())
+    }
+
+    pub async fn set_rate(&self, rate: GyroRate) -> Result<(), GyroError> {
+        match self.chip()? {
+            // DR -> output data rate
+            // 10 -> medium bandwidth
+            // 1 -> normal mode
+            // 111 -> all axes enabled
+            GyroChip::L3gd20h => self.write_reg(L3GD20H_CTRL1, rate.bits() << 6 | 0b00101111).await?,
+            GyroChip::Lsm6ds33 => self.write_lsm6ds33_ctrl2(rate, self.range.get()).await?,
+        }
+        self.rate.set(rate);
+        Ok(())
+    }
+
+    pub async fn set_range(&self, range: GyroRange) -> Result<(), GyroError> {
+        match self.chip()? {
+            GyroChip::L3gd20h => {
+                let bits = range.bits(GyroChip::L3gd20h);
+                self.write_reg(L3GD20H_CTRL4, bits << 4).await?
+            },
+            GyroChip::Lsm6ds33 => self.write_lsm6ds33_ctrl2(self.rate.get(), range).await?,
+        }
+        self.range.set(range);
+        Ok(())
+    }
+
+    pub fn set_bias(&self, bias: (f32, f32, f32)) {
+        self.bias.set(bias);
+    }
+
+    // The robot must be sitting still while this runs
+    pub async fn calibrate_bias(&self) -> Result<(f32, f32, f32), GyroError> {
+        let (mut x_sum, mut y_sum, mut z_sum) = (0.0, 0.0, 0.0);
+        for _ in 0..BIAS_CALIBRATION_SAMPLES {
+            while !self.is_ready().await? {
+                Waiter::new(1).await;
+            }
+            let (x, y, z) = self.read_raw_rates().await?;
+            x_sum += x;
+            y_sum += y;
+            z_sum += z;
+        }
+
+        let n = BIAS_CALIBRATION_SAMPLES as f32;
+        let bias = (x_sum / n, y_sum / n, z_sum / n);
+        self.bias.set(bias);
+        Ok(bias)
+    }
+
+    // Angular rate about each axis in degrees per second, with the bias removed
+    pub async fn read_rates(&self) -> Result<(f32, f32, f32), GyroError> {
+        let (x, y, z) = self.read_raw_rates().await?;
+        let (bx, by, bz) = self.bias.get();
+        Ok((x - bx, y - by, z - bz))
+    }
+
+    pub fn yaw_degrees(&self) -> f32 {
+        self.yaw_degrees.get()
+    }
+
+    pub fn reset_yaw(&self) {
+        self.yaw_degrees.set(0.0);
+    }
+
+    // False if the last attempt to talk to the gyro failed; the yaw angle isn't being updated
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.get()
+    }
+
+    pub async fn is_ready(&self) -> Result<bool, GyroError> {
+        let (status_reg, ready_mask) = self.chip()?.status();
+        let mut data: [u8; 1] = [0];
+        self.twi.write_read(GYRO_ADDR, &[status_reg], &mut data).await?;
+        Ok((data[0] & ready_mask) > 0)
+    }
+
+    pub fn get_gyro_driver(&'static self) -> &'static mut dyn Future<Output = !> {
+        let future = async move || loop {
+            if !self.configured.get() && timers::millis() >= self.next_init_ms.get() {
+                self.next_init_ms.set(timers::millis() + REINIT_DELAY_MS);
+                self.init().await.ok();
+            }
+
+            if self.configured.get() {
+                match self.update_yaw().await {
+                    Ok(()) => self.healthy.set(true),
+                    Err(_) => {
+                        // Whatever happened to the yaw while we weren't looking is lost, so start
+                        // integrating over from the next good sample
+                        self.healthy.set(false);
+                        self.configured.set(false);
+                        self.last_update_us.set(0);
+                    },
+                }
+            }
+            Waiter::new(self.rate.get().period_ms()).await;
+        };
+        Allocator::get().new(future())
+    }
+
+    async fn update_yaw(&self) -> Result<(), GyroError> {
+        if !self.is_ready().await? {
+            return Ok(());
+        }
+        let now = timers::micros();
+        let (_, _, z) = self.read_rates().await?;
+
+        // The first sample just establishes a reference time
+        if self.last_update_us.get() != 0 {
+            let dt = now.wrapping_sub(self.last_update_us.get()) as f32 / 1_000_000.0;
+            let mut yaw = self.yaw_degrees.get() + z * dt;
+            if yaw >= 360.0 {
+                yaw -= 360.0;
+            } else if yaw < 0.0 {
+                yaw += 360.0;
+            }
+            self.yaw_degrees.set(yaw);
+        }
+        self.last_update_us.set(now);
+        Ok(())
+    }
+
+    // Rates in dps without bias correction
+    async fn read_raw_rates(&self) -> Result<(f32, f32, f32), GyroError> {
+        let out_reg = self.chip()?.out_reg();
+        let mut data: [u8; 6] = [0; 6];
+        self.twi.write_read(GYRO_ADDR, &[out_reg], &mut data).await?;
+        let scale = self.range.get().scale();
+        Ok((
+            (((data[1] as u16) << 8 | data[0] as u16) as i16) as f32 * scale,
+            (((data[3] as u16) << 8 | data[2] as u16) as i16) as f32 * scale,
+            (((data[5] as u16) << 8 | data[4] as u16) as i16) as f32 * scale,
+        ))
+    }
+
+    fn chip(&self) -> Result<GyroChip, GyroError> {
+        self.chip.get().ok_or(GyroError::NotDetected)
+    }
+
+    // The LSM6DS33 sets the rate and range in the same register
+    async fn write_lsm6ds33_ctrl2(&self, rate: GyroRate, range: GyroRange) -> Result<(), GyroError> {
+        // ODR_G -> output data rate
+        // FS_G -> range
+        // 00 -> 125dps range off
+        let odr_bits = 0b0100 | rate.bits();
+        let value = odr_bits << 4 | range.bits(GyroChip::Lsm6ds33) << 2;
+        self.write_reg(LSM6DS33_CTRL2_G, value).await
+    }
+
+    async fn write_reg(&self, reg: u8, value: u8) -> Result<(), GyroError> {
+        self.twi.write(GYRO_ADDR, &[reg, value]).await?;
+        Ok(())
+    }
+}
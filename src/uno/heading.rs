@@ -0,0 +1,109 @@
+use crate::{
+    avr_async::Waiter,
+    math,
+    mem::Allocator,
+    params::{
+        self,
+        Param,
+    },
+    uno::{
+        gyro::Gyro,
+        imu::IMU,
+        timers,
+        MotorController,
+    },
+};
+use core::{
+    cell::Cell,
+    future::Future,
+};
+
+const UPDATE_DELAY_MS: u32 = 20; // The magnetometer's output data rate is 50Hz, or 1 / 20ms.
+const IMU_REINIT_DELAY_MS: u32 = 1000;
+
+// A complementary filter that fuses the gyro and the (tilt-compensated) magnetometer into a single
+// compass heading.  Each update the gyro's change in yaw is applied directly, and then the estimate
+// is nudged a small fraction of the way towards the magnetometer's heading.  The gyro handles
+// short-term changes (and carries the estimate through when the motors are disturbing the
+// magnetometer), while the magnetometer corrects the gyro's long-term drift.  If the magnetometer
+// drops out we carry on with just the gyro, and if the gyro drops out the heading stops tracking.
+pub struct HeadingEstimator {
+    imu: &'static IMU,
+    gyro: &'static Gyro,
+    motor_controller: &'static MotorController,
+    heading_degrees: Cell<f32>,
+    last_yaw_degrees: Cell<f32>,
+    initialized: Cell<bool>,
+    next_imu_init_ms: Cell<u32>,
+}
+
+impl HeadingEstimator {
+    // Like the MotorController, this is a static reference because the driver holds onto "self"
+    pub fn new(
+        imu: &'static IMU,
+        gyro: &'static Gyro,
+        motor_controller: &'static MotorController,
+    ) -> &'static HeadingEstimator {
+        Allocator::get().new(HeadingEstimator {
+            imu,
+            gyro,
+            motor_controller,
+            heading_degrees: Cell::new(0.0),
+            last_yaw_degrees: Cell::new(gyro.yaw_degrees()),
+            initialized: Cell::new(false),
+            next_imu_init_ms: Cell::new(0),
+        })
+    }
+
+    // Compass heading in degrees, clockwise from north
+    pub fn heading_degrees(&self) -> f32 {
+        self.heading_degrees.get()
+    }
+
+    // False until we've gotten at least one good magnetometer reading
+    pub fn is_initialized(&self) -> bool {
+        self.initialized.get()
+    }
+
+    // False if the gyro isn't responding, in which case the heading is frozen; relative turns
+    // are still fine as long as this is true, even if the magnetometer is out
+    pub fn is_tracking(&self) -> bool {
+        self.gyro.is_healthy()
+    }
+
+    pub fn get_heading_driver(&'static self) -> &'static mut dyn Future<Output = !> {
+        let future = async move || loop {
+            // Yaw is counterclockwise-positive, heading is clockwise-positive
+            let yaw = self.gyro.yaw_degrees();
+            let mut heading = self.heading_degrees.get() - math::degrees_delta(self.last_yaw_degrees.get(), yaw);
+            self.last_yaw_degrees.set(yaw);
+
+            if self.imu.is_calibrating() {
+                // The magnetometer calibration is being replaced, so don't trust it
+            } else if !self.imu.is_configured() {
+                // Try to bring the IMU back (e.g., after a loose cable), but not so often that we hog
+                // the bus
+                if timers::millis() >= self.next_imu_init_ms.get() {
+                    self.next_imu_init_ms.set(timers::millis() + IMU_REINIT_DELAY_MS);
+                    self.imu.init().await.ok();
+                }
+            } else if let Some(mag_heading) = self.imu.read_heading_degrees() {
+                if self.initialized.get() {
+                    let weight = if self.motor_controller.is_driving() {
+                        params::get(Param::FusionMagWeightDriving)
+                    } else {
+                        params::get(Param::FusionMagWeight)
+                    };
+                    heading += weight * math::degrees_delta(heading, mag_heading);
+                } else {
+                    heading = mag_heading;
+                    self.initialized.set(true);
+                }
+            }
+
+            self.heading_degrees.set(math::wrap_degrees(heading));
+            Waiter::new(UPDATE_DELAY_MS).await;
+        };
+        Allocator::get().new(future())
+    }
+}
@@ -1,142 +1,484 @@
-use crate::Waiter;
-use arduino_uno::{
-    pac::EEPROM,
-    prelude::*,
+use crate::{
+    avr_async::block_on,
+    math::{
+        self,
+        Vector3,
+    },
+    mem::Allocator,
+    params::{
+        self,
+        Param,
+    },
+    sample_ring::{
+        FilterKind,
+        SampleRing,
+    },
+    uno::{
+        imu_chips::{
+            ImuChip,
+            RegWrite,
+            Sensor,
+            IMU_CHIPS,
+        },
+        mag_calibration::{
+            CalibrationQuality,
+            EllipseFit,
+            MagCalibration,
+        },
+        timers,
+        twi::{
+            Twi,
+            TwiError,
+        },
+    },
+    Waiter,
 };
-use avr_hal_generic::port::mode::{
-    Input,
-    PullUp,
+use core::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    future::Future,
 };
 use micromath::F32Ext;
 
 const PI: f32 = 3.1415926;
-const MAG_ACC_ADDR: u8 = 0b0011101; // Magnetometer/accelerometer
-const MAG_ACC_CTRL0: u8 = 0x1f;
-const MAG_STATUS_REG: u8 = 0x07;
-const MAG_REG_OUT: u8 = 0x08;
+const ACC_POLL_DELAY_MS: u32 = 2;
 
-const GYRO_ADDR: u8 = 0b1101011; // Gyroscope
-
-const TOTAL_CALIBRATION_SAMPLES: u32 = 100;
+// We use the first part of the calibration spin to get a rough idea of the center and size of the
+// ellipse, and the rest to fit it
+const ROUGH_CALIBRATION_SAMPLES: u32 = 50;
+const FIT_CALIBRATION_SAMPLES: u32 = 100;
 const TIME_BETWEEN_SAMPLES_MS: u32 = 50;
-const SMOOTHING_ITERS: u8 = 10;
+const MAG_STRENGTH_TOLERANCE: f32 = 0.4; // calibrated field strength is nominally 1
+
+// The self-tests average a few samples with the test off and on, after giving the sensor time to
+// settle into each mode
+const SELF_TEST_SAMPLES: u8 = 5;
+const SELF_TEST_SETTLE_MS: u32 = 100;
+pub const MAX_SELF_TESTS: usize = 2;
+
+// The sampling driver checks for new magnetometer data a few times per 50Hz sample, and keeps the
+// last several around for filtering
+pub const MAG_RING_LEN: usize = 8;
+const MAG_POLL_DELAY_MS: u32 = 5;
+const MAG_MAX_SAMPLE_AGE_MS: u32 = 100; // older than this and the magnetometer has probably stopped
+
+// Likewise, the accelerometer is sampled once per 50Hz sample for everything that needs it, rather
+// than each of them reading it over the bus separately
+const ACC_SAMPLE_DELAY_MS: u32 = 20;
+const ACC_MAX_SAMPLE_AGE_MS: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImuError {
+    Bus(TwiError),
+    NotDetected,      // none of the chips we know about answered
+    UnsupportedRange, // the attached chip can't do the requested accelerometer range
+}
+
+impl From<TwiError> for ImuError {
+    fn from(e: TwiError) -> ImuError {
+        ImuError::Bus(e)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum AccelRange {
+    G2,
+    G4,
+    G6,
+    G8,
+    G16,
+}
+
+#[derive(Clone, Copy)]
+pub struct AccelSample {
+    pub value: Vector3, // g
+    pub timestamp_ms: u32,
+    pub sequence: u32, // goes up by one with every sample, starting at 1
+}
+
+#[derive(Clone, Copy)]
+pub struct SelfTestResult {
+    pub name: &'static str,
+    pub passed: bool,
+}
+
+// Clears IMU::calibrating however the calibration ends, including when the state running it gets
+// preempted and drops it partway through
+struct CalibratingGuard<'a>(&'a Cell<bool>);
+
+impl<'a> Drop for CalibratingGuard<'a> {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
 
+// The IMU shares the I2C bus with the gyro and is used both by the heading estimator and by the
+// states, so all of its state lives in Cells and it's handed around as a static reference.
+//
+// There are a few different accelerometer/magnetometer chips on the boards we might be using; the
+// first call to `init` works out which one is attached (see imu_chips.rs).
+//
+// The magnetometer is sampled in the background whenever it reports new data, so headings come
+// from a filtered window of recent samples instead of a burst of reads on the spot.  The
+// accelerometer is sampled in the background too, and the latest sample is shared by the heading
+// estimator and the bump, orientation and tap monitors.
+//
+// Every bus access can fail (e.g., if a cable works loose); errors are handed back to the caller,
+// and the chip is marked as needing to be reconfigured since it may have lost power.
 pub struct IMU {
-    i2c: arduino_uno::I2cMaster<Input<PullUp>>,
-    x_min: f32,
-    x_range: f32,
-    y_min: f32,
-    y_range: f32,
+    twi: &'static Twi,
+    chip: Cell<Option<&'static dyn ImuChip>>,
+    accel_range: Cell<AccelRange>,
+    mag_calibration: Cell<MagCalibration>,
+    calibrating: Cell<bool>,
+    configured: Cell<bool>,
+    mag_samples: RefCell<SampleRing<MAG_RING_LEN>>,
+    accel_sample: Cell<Option<AccelSample>>,
 }
 
 impl IMU {
-    pub fn new(mut i2c: arduino_uno::I2cMaster<Input<PullUp>>) -> IMU {
-        // Accelerometer
-
-        // 0101 -> 50Hz output data rate
-        // 0111 -> all axes enabled
-        i2c.write(MAG_ACC_ADDR, &[MAG_ACC_CTRL0 + 1, 0b01010111])
-            .expect("write failed");
-
-        // Magnetometer
-
-        // 0 -> disable temperature sensor
-        // 11 -> high resolution mode
-        // 100 -> 50Hz output data rate
-        // 00 -> no interrupt requests are latched
-        i2c.write(MAG_ACC_ADDR, &[MAG_ACC_CTRL0 + 5, 0x70])
-            .expect("write failed");
-
-        // 0x20 = +/- 4 gauss range
-        i2c.write(MAG_ACC_ADDR, &[MAG_ACC_CTRL0 + 6, 0x20])
-            .expect("write failed");
-
-        // 0x00 = continuous-conversion mode (constantly taking readings)
-        i2c.write(MAG_ACC_ADDR, &[MAG_ACC_CTRL0 + 7, 0x00])
-            .expect("write failed");
-
-        IMU {
-            i2c,
-            x_min: 0.0,
-            x_range: 0.0,
-            y_min: 0.0,
-            y_range: 0.0,
+    pub fn new(twi: &'static Twi) -> &'static IMU {
+        let imu = Allocator::get().new(IMU {
+            twi,
+            chip: Cell::new(None),
+            accel_range: Cell::new(AccelRange::G2),
+            mag_calibration: Cell::new(MagCalibration {
+                offset: (0.0, 0.0),
+                matrix: [1.0, 0.0, 0.0, 1.0],
+                z_level: 0.0,
+            }),
+            calibrating: Cell::new(false),
+            configured: Cell::new(false),
+            mag_samples: RefCell::new(SampleRing::new()),
+            accel_sample: Cell::new(None),
+        });
+
+        // This runs before the executor starts, so we have to drive the bus by hand; if it fails,
+        // whoever uses the IMU next can call init again
+        block_on(imu.init()).ok();
+        imu
+    }
+
+    pub async fn init(&self) -> Result<(), ImuError> {
+        let chip = match self.chip.get() {
+            Some(chip) => chip,
+            None => {
+                let chip = self.detect().await?;
+                self.chip.set(Some(chip));
+                chip
+            },
+        };
+
+        for write in chip.config() {
+            self.write_reg(write).await?;
         }
+        let range_write = chip
+            .accel_range_write(self.accel_range.get())
+            .ok_or(ImuError::UnsupportedRange)?;
+        self.write_reg(&range_write).await?;
+
+        self.configured.set(true);
+        Ok(())
     }
 
-    pub async fn get_calibration_vector(&mut self) -> (i16, i16, i16, i16) {
-        let (mut x_min, mut x_max, mut y_min, mut y_max) = (i16::MAX, 0i16, i16::MAX, 0i16);
-        for _ in 0..TOTAL_CALIBRATION_SAMPLES {
-            let (x, y, _) = self.read_magnetometer();
-            if x < x_min {
-                x_min = x
-            } else if x > x_max {
-                x_max = x
+    // The name of the attached chip, if we've found it
+    pub fn chip_name(&self) -> Option<&'static str> {
+        self.chip.get().map(|chip| chip.name())
+    }
+
+    async fn detect(&self) -> Result<&'static dyn ImuChip, ImuError> {
+        for &chip in IMU_CHIPS {
+            let mut matches = true;
+            for probe in chip.probes() {
+                let mut data: [u8; 1] = [0];
+                // A NACK just means there's nothing at that address, so move on to the next chip
+                match self.twi.write_read(probe.addr, &[probe.reg], &mut data).await {
+                    Ok(()) if data[0] == probe.expected => (),
+                    Ok(()) | Err(TwiError::AddressNack) => matches = false,
+                    Err(e) => return Err(e.into()),
+                }
+                if !matches {
+                    break;
+                }
+            }
+            if matches {
+                return Ok(chip);
+            }
+        }
+        Err(ImuError::NotDetected)
+    }
+
+    // Runs each of the chip's built-in self-tests; the robot should be sitting still.  The chip is
+    // reconfigured afterwards, since the tests change its settings.
+    pub async fn self_test(&self) -> Result<[Option<SelfTestResult>; MAX_SELF_TESTS], ImuError> {
+        let chip = self.chip.get().ok_or(ImuError::NotDetected)?;
+        let mut results = [None; MAX_SELF_TESTS];
+        for (test, result) in chip.self_tests().iter().zip(results.iter_mut()) {
+            for write in test.setup {
+                self.write_reg(write).await?;
+            }
+            let off = self.average_samples(chip, test.sensor).await?;
+            for write in test.enable {
+                self.write_reg(write).await?;
             }
-            if y < y_min {
-                y_min = y
-            } else if y > y_max {
-                y_max = y
+            let on = self.average_samples(chip, test.sensor).await?;
+            for write in test.disable {
+                self.write_reg(write).await?;
             }
+            self.init().await?;
+
+            *result = Some(SelfTestResult {
+                name: test.name,
+                passed: test.passed(off, on),
+            });
+        }
+        Ok(results)
+    }
+
+    async fn average_samples(&self, chip: &'static dyn ImuChip, sensor: Sensor) -> Result<Vector3, ImuError> {
+        Waiter::new(SELF_TEST_SETTLE_MS).await;
+        let mut sum = (0.0, 0.0, 0.0);
+        for _ in 0..SELF_TEST_SAMPLES {
+            while !self.is_sensor_ready(chip, sensor).await? {
+                Waiter::new(ACC_POLL_DELAY_MS).await;
+            }
+            let (x, y, z) = self.read_sensor(chip, sensor).await?;
+            sum = math::add(sum, (x as f32, y as f32, z as f32));
+        }
+        Ok(math::scale(sum, 1.0 / SELF_TEST_SAMPLES as f32))
+    }
+
+    // False if the chip hasn't been set up since the last bus error
+    pub fn is_configured(&self) -> bool {
+        self.configured.get()
+    }
+
+    // The robot should be spinning in place while this runs; returns None if the samples don't
+    // fit an ellipse (e.g., because the robot wasn't actually turning)
+    pub async fn calibrate_magnetometer(&self) -> Result<Option<(MagCalibration, CalibrationQuality)>, ImuError> {
+        self.calibrating.set(true);
+        let _calibrating = CalibratingGuard(&self.calibrating);
+        self.fit_magnetometer_calibration().await
+    }
+
+    // True while calibrate_magnetometer is running, when headings aren't meaningful
+    pub fn is_calibrating(&self) -> bool {
+        self.calibrating.get()
+    }
+
+    async fn fit_magnetometer_calibration(&self) -> Result<Option<(MagCalibration, CalibrationQuality)>, ImuError> {
+        let (mut x_min, mut x_max, mut y_min, mut y_max) = (i16::MAX, i16::MIN, i16::MAX, i16::MIN);
+        for _ in 0..ROUGH_CALIBRATION_SAMPLES {
+            let (x, y, _) = self.read_magnetometer().await?;
+            x_min = x_min.min(x);
+            x_max = x_max.max(x);
+            y_min = y_min.min(y);
+            y_max = y_max.max(y);
+            Waiter::new(TIME_BETWEEN_SAMPLES_MS).await;
+        }
+
+        let center = ((x_min as f32 + x_max as f32) / 2.0, (y_min as f32 + y_max as f32) / 2.0);
+        let x_radius = (x_max as f32 - x_min as f32) / 2.0;
+        let y_radius = (y_max as f32 - y_min as f32) / 2.0;
+        let mut fit = EllipseFit::new(center, if x_radius > y_radius { x_radius } else { y_radius });
+        for _ in 0..FIT_CALIBRATION_SAMPLES {
+            let (x, y, z) = self.read_magnetometer().await?;
+            fit.add(x as f32, y as f32, z as f32);
             Waiter::new(TIME_BETWEEN_SAMPLES_MS).await;
         }
 
-        (x_min, x_max, y_min, y_max)
+        Ok(fit.solve())
+    }
+
+    pub fn set_mag_calibration(&self, calibration: MagCalibration) {
+        self.mag_calibration.set(calibration);
+    }
+
+    // The filtered magnetometer reading (in raw counts) over the last "mag_window" samples, or None
+    // if the sampling driver hasn't gotten anything recently
+    pub fn filtered_magnetometer(&self) -> Option<Vector3> {
+        let samples = self.mag_samples.borrow();
+        let latest = samples.latest()?;
+        if timers::millis().wrapping_sub(latest.timestamp_ms) > MAG_MAX_SAMPLE_AGE_MS {
+            return None;
+        }
+
+        let kind = if params::get_u32(Param::MagFilterMedian) != 0 {
+            FilterKind::Median
+        } else {
+            FilterKind::MovingAverage
+        };
+        samples.filter(kind, params::get_u32(Param::MagFilterWindow) as usize)
+    }
+
+    pub fn get_mag_sampling_driver(&'static self) -> &'static mut dyn Future<Output = !> {
+        let future = async move || loop {
+            // Errors mark the IMU as unconfigured, and the heading estimator takes care of bringing
+            // it back
+            if self.is_configured() {
+                if let Ok(true) = self.is_magnetometer_ready().await {
+                    if let Ok(value) = self.read_magnetometer().await {
+                        self.mag_samples.borrow_mut().push(timers::millis(), value);
+                    }
+                }
+            }
+            Waiter::new(MAG_POLL_DELAY_MS).await;
+        };
+        Allocator::get().new(future())
+    }
+
+    // The latest accelerometer sample, or None if the sampling driver hasn't gotten anything
+    // recently
+    pub fn latest_accelerometer(&self) -> Option<AccelSample> {
+        let sample = self.accel_sample.get()?;
+        if timers::millis().wrapping_sub(sample.timestamp_ms) > ACC_MAX_SAMPLE_AGE_MS {
+            return None;
+        }
+        Some(sample)
+    }
+
+    // Waits for a sample other than the one numbered `sequence` (pass 0 to take whatever's there);
+    // this waits for as long as the IMU is down
+    pub async fn next_accelerometer(&self, sequence: u32) -> AccelSample {
+        loop {
+            match self.latest_accelerometer() {
+                Some(sample) if sample.sequence != sequence => return sample,
+                _ => Waiter::new(ACC_POLL_DELAY_MS).await,
+            }
+        }
+    }
+
+    pub fn get_accel_sampling_driver(&'static self) -> &'static mut dyn Future<Output = !> {
+        let future = async move || loop {
+            if self.is_configured() {
+                if let Ok(value) = self.read_accelerometer().await {
+                    let sequence = self.accel_sample.get().map_or(0, |sample| sample.sequence);
+                    self.accel_sample.set(Some(AccelSample {
+                        value,
+                        timestamp_ms: timers::millis(),
+                        sequence: sequence.wrapping_add(1).max(1),
+                    }));
+                }
+            }
+            Waiter::new(ACC_SAMPLE_DELAY_MS).await;
+        };
+        Allocator::get().new(future())
+    }
+
+    pub async fn set_accelerometer_range(&self, range: AccelRange) -> Result<(), ImuError> {
+        let write = self
+            .chip()?
+            .accel_range_write(range)
+            .ok_or(ImuError::UnsupportedRange)?;
+        self.write_reg(&write).await?;
+        self.accel_range.set(range);
+        Ok(())
     }
 
-    pub fn set_calibration_vector(&mut self, vector: (i16, i16, i16, i16)) {
-        self.x_min = vector.0 as f32;
-        self.x_range = (vector.1 - vector.0) as f32;
-        self.y_min = vector.2 as f32;
-        self.y_range = (vector.3 - vector.2) as f32;
+    // Acceleration on each axis in g
+    pub async fn read_accelerometer(&self) -> Result<(f32, f32, f32), ImuError> {
+        let (x, y, z) = self.read_accelerometer_raw().await?;
+        let scale = self.chip()?.accel_scale(self.accel_range.get());
+        Ok((x as f32 * scale, y as f32 * scale, z as f32 * scale))
     }
 
-    pub fn get_current_heading_degrees(&mut self) -> f32 {
-        let (mut avg_x, mut avg_y) = (0.0, 0.0);
-        for _ in 0..SMOOTHING_ITERS {
-            let (x, y, _) = self.read_axes_16_bit(MAG_ACC_ADDR, MAG_REG_OUT);
-            avg_x += x as f32;
-            avg_y += y as f32;
+    // Waits for a new sample instead of re-reading the previous one
+    pub async fn read_accelerometer_when_ready(&self) -> Result<(f32, f32, f32), ImuError> {
+        while !self.is_accelerometer_ready().await? {
+            Waiter::new(ACC_POLL_DELAY_MS).await;
         }
-        avg_x /= SMOOTHING_ITERS as f32;
-        avg_y /= SMOOTHING_ITERS as f32;
-        self.compute_heading_degrees(avg_x, avg_y)
+        self.read_accelerometer().await
     }
 
-    pub fn read_magnetometer(&mut self) -> (i16, i16, i16) {
-        self.read_axes_16_bit(MAG_ACC_ADDR, MAG_REG_OUT)
+    pub async fn read_accelerometer_raw(&self) -> Result<(i16, i16, i16), ImuError> {
+        self.read_sensor(self.chip()?, Sensor::Accelerometer).await
     }
 
-    pub fn is_magnetometer_ready(&mut self) -> bool {
+    pub async fn is_accelerometer_ready(&self) -> Result<bool, ImuError> {
+        self.is_sensor_ready(self.chip()?, Sensor::Accelerometer).await
+    }
+
+    pub async fn read_magnetometer(&self) -> Result<(i16, i16, i16), ImuError> {
+        self.read_sensor(self.chip()?, Sensor::Magnetometer).await
+    }
+
+    pub async fn is_magnetometer_ready(&self) -> Result<bool, ImuError> {
+        self.is_sensor_ready(self.chip()?, Sensor::Magnetometer).await
+    }
+
+    fn chip(&self) -> Result<&'static dyn ImuChip, ImuError> {
+        self.chip.get().ok_or(ImuError::NotDetected)
+    }
+
+    async fn is_sensor_ready(&self, chip: &'static dyn ImuChip, sensor: Sensor) -> Result<bool, ImuError> {
+        let regs = chip.sensor_regs(sensor);
         let mut data: [u8; 1] = [0];
-        self.i2c
-            .write_read(MAG_ACC_ADDR, &[MAG_STATUS_REG], &mut data)
-            .expect("write_read failed");
-        (data[0] & 0x08) > 0
+        self.checked(self.twi.write_read(regs.addr, &[regs.status_reg], &mut data).await)?;
+        Ok((data[0] & regs.ready_mask) > 0)
     }
 
-    fn read_axes_16_bit(&mut self, addr: u8, reg: u8) -> (i16, i16, i16) {
+    async fn read_sensor(&self, chip: &'static dyn ImuChip, sensor: Sensor) -> Result<(i16, i16, i16), ImuError> {
+        let regs = chip.sensor_regs(sensor);
         let mut data: [u8; 6] = [0; 6];
-        self.i2c
-            .write_read(addr, &[reg | 0x80], &mut data)
-            .expect("write_read failed");
-        (
-            ((data[1] as u16) << 8 | data[0] as u16) as i16,
-            ((data[3] as u16) << 8 | data[2] as u16) as i16,
-            ((data[5] as u16) << 8 | data[4] as u16) as i16,
-        )
-    }
-
-    fn compute_heading_degrees(&mut self, x: f32, y: f32) -> f32 {
-        let (x_scaled, y_scaled) = (
-            2.0 * (x - self.x_min) / self.x_range - 1.0,
-            2.0 * (y - self.y_min) / self.y_range - 1.0,
-        );
-
-        let mut angle = x_scaled.atan2(y_scaled) * 180.0 / PI;
+        self.checked(self.twi.write_read(regs.addr, &[regs.out_reg], &mut data).await)?;
+        Ok(chip.decode(sensor, &data))
+    }
+
+    async fn write_reg(&self, write: &RegWrite) -> Result<(), ImuError> {
+        self.checked(self.twi.write(write.addr, &[write.reg, write.value]).await)
+    }
+
+    fn checked<T>(&self, result: Result<T, TwiError>) -> Result<T, ImuError> {
+        if result.is_err() {
+            self.configured.set(false);
+        }
+        Ok(result?)
+    }
+
+    // A tilt-compensated heading from the filtered magnetometer samples; returns None if there
+    // aren't any recent samples, or if the horizontal field strength doesn't match what we saw
+    // during calibration, which usually means the motors (or something else nearby) are swamping
+    // the earth's field
+    pub fn read_heading_degrees(&self) -> Option<f32> {
+        let mag = self.filtered_magnetometer()?;
+        let gravity = self.latest_accelerometer()?.value;
+        let (x_horizontal, y_horizontal) = self.horizontal_field(mag, gravity);
+
+        let strength = (x_horizontal * x_horizontal + y_horizontal * y_horizontal).sqrt();
+        if strength < 1.0 - MAG_STRENGTH_TOLERANCE || strength > 1.0 + MAG_STRENGTH_TOLERANCE {
+            return None;
+        }
+
+        let mut angle = x_horizontal.atan2(y_horizontal) * 180.0 / PI;
         if angle < 0.0 {
             angle += 360.0;
         }
-        angle
+        Some(angle)
     }
+
+    // The magnetometer reading is projected onto the horizontal plane (perpendicular to gravity)
+    // before computing the heading, so it stays correct when the robot is tilted.  The horizontal
+    // "forward" axis is the robot's x-axis with its vertical component removed, and the horizontal
+    // y-axis completes a right-handed frame with gravity; when the robot is level these are just the
+    // x- and y-axes of the sensor.
+    fn horizontal_field(&self, mag: Vector3, accel: Vector3) -> (f32, f32) {
+        // The field dips below horizontal by the inclination, i.e. it points away from "up" (the
+        // accelerometer's z-axis) in the northern hemisphere
+        let inclination = params::get(Param::MagInclination) * PI / 180.0;
+        let m = self.mag_calibration.get().apply(mag, -inclination.tan());
+
+        match horizontal_axes(accel) {
+            Some((forward, left)) => (math::dot(m, forward), math::dot(m, left)),
+            None => (m.0, m.1), // we're pointing straight up or down, so just ignore the tilt
+        }
+    }
+}
+
+// Unit vectors (in sensor coordinates) along the horizontal projections of the x- and y-axes
+fn horizontal_axes(accel: Vector3) -> Option<(Vector3, Vector3)> {
+    let up = math::normalize(accel)?;
+    let forward = math::normalize(math::sub((1.0, 0.0, 0.0), math::scale(up, up.0)))?;
+    Some((forward, math::cross(up, forward)))
 }
@@ -0,0 +1,395 @@
+// Register maps for the accelerometer/magnetometer combinations found on the various Pololu IMU
+// boards.  The IMU driver figures out which one is attached by checking identification registers
+// at boot, and from then on does all of its bus traffic through the matching ImuChip.
+//
+// The boards with an LSM6DS33 also have their gyro in that chip, in place of an L3GD20H; the Gyro
+// driver looks after that half, and only the LSM6DS33's accelerometer is used here.
+use crate::{
+    math::Vector3,
+    uno::imu::AccelRange,
+};
+use micromath::F32Ext;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sensor {
+    Accelerometer,
+    Magnetometer,
+}
+
+#[derive(Clone, Copy)]
+pub struct RegWrite {
+    pub addr: u8,
+    pub reg: u8,
+    pub value: u8,
+}
+
+const fn reg_write(addr: u8, reg: u8, value: u8) -> RegWrite {
+    RegWrite { addr, reg, value }
+}
+
+// A register that reads back a fixed value on the chip we're looking for
+pub struct Probe {
+    pub addr: u8,
+    pub reg: u8,
+    pub expected: u8,
+}
+
+// Where to find a sensor's data; `out_reg` includes the auto-increment bit if the chip needs one
+pub struct SensorRegs {
+    pub addr: u8,
+    pub status_reg: u8,
+    pub ready_mask: u8,
+    pub out_reg: u8,
+}
+
+// The built-in self-test applies a known force (or field) to the sensor; we compare the average
+// output with the self-test off and on, and the change on each axis has to land within `limits`.
+// `setup` puts the sensor in the mode the limits were specified for.  Afterwards `disable` turns
+// the self-test back off and the chip is reconfigured from scratch.
+pub struct SelfTest {
+    pub name: &'static str,
+    pub sensor: Sensor,
+    pub setup: &'static [RegWrite],
+    pub enable: &'static [RegWrite],
+    pub disable: &'static [RegWrite],
+    pub limits: [(f32, f32); 3], // raw counts
+}
+
+impl SelfTest {
+    pub fn passed(&self, off: Vector3, on: Vector3) -> bool {
+        let change = [(on.0 - off.0).abs(), (on.1 - off.1).abs(), (on.2 - off.2).abs()];
+        change
+            .iter()
+            .zip(self.limits.iter())
+            .all(|(&c, &(min, max))| min <= c && c <= max)
+    }
+}
+
+pub trait ImuChip {
+    fn name(&self) -> &'static str;
+
+    // Every probe has to match for the chip to be detected
+    fn probes(&self) -> &'static [Probe];
+
+    // Continuous sampling at (at least) 50Hz on both sensors, and a +/- 4 gauss magnetometer range
+    fn config(&self) -> &'static [RegWrite];
+
+    // None if the chip doesn't support the range
+    fn accel_range_write(&self, range: AccelRange) -> Option<RegWrite>;
+
+    // g/LSB
+    fn accel_scale(&self, range: AccelRange) -> f32;
+
+    fn sensor_regs(&self, sensor: Sensor) -> SensorRegs;
+
+    fn self_tests(&self) -> &'static [SelfTest];
+
+    // Most chips report x, y, z as little-endian 16-bit values
+    fn decode(&self, _sensor: Sensor, data: &[u8; 6]) -> (i16, i16, i16) {
+        little_endian_xyz(data)
+    }
+}
+
+fn little_endian_xyz(data: &[u8; 6]) -> (i16, i16, i16) {
+    (
+        ((data[1] as u16) << 8 | data[0] as u16) as i16,
+        ((data[3] as u16) << 8 | data[2] as u16) as i16,
+        ((data[5] as u16) << 8 | data[4] as u16) as i16,
+    )
+}
+
+// Tried in order; the LSM303DLHC's accelerometer doesn't have an ID register, so it goes last
+pub const IMU_CHIPS: &[&dyn ImuChip] = &[&Lsm303d, &Lsm6ds33Lis3mdl, &Lsm303dlhc];
+
+// Zumo 32U4 and MinIMU-9 v3: accelerometer and magnetometer in one chip
+pub struct Lsm303d;
+
+const LSM303D_ADDR: u8 = 0b0011101;
+const LSM303D_CTRL0: u8 = 0x1f;
+
+impl ImuChip for Lsm303d {
+    fn name(&self) -> &'static str {
+        "LSM303D"
+    }
+
+    fn probes(&self) -> &'static [Probe] {
+        &[Probe {
+            addr: LSM303D_ADDR,
+            reg: 0x0f, // WHO_AM_I
+            expected: 0x49,
+        }]
+    }
+
+    fn config(&self) -> &'static [RegWrite] {
+        &[
+            // Accelerometer
+            // 0101 -> 50Hz output data rate
+            // 0111 -> all axes enabled
+            reg_write(LSM303D_ADDR, LSM303D_CTRL0 + 1, 0b01010111),
+            // Magnetometer
+            // 0 -> disable temperature sensor
+            // 11 -> high resolution mode
+            // 100 -> 50Hz output data rate
+            // 00 -> no interrupt requests are latched
+            reg_write(LSM303D_ADDR, LSM303D_CTRL0 + 5, 0x70),
+            // 0x20 = +/- 4 gauss range
+            reg_write(LSM303D_ADDR, LSM303D_CTRL0 + 6, 0x20),
+            // 0x00 = continuous-conversion mode (constantly taking readings)
+            reg_write(LSM303D_ADDR, LSM303D_CTRL0 + 7, 0x00),
+        ]
+    }
+
+    fn accel_range_write(&self, range: AccelRange) -> Option<RegWrite> {
+        // 00 -> 773Hz anti-alias filter
+        // AFS bits -> range
+        // 000 -> self-test off, 4-wire SPI
+        let bits = match range {
+            AccelRange::G2 => 0b000,
+            AccelRange::G4 => 0b001,
+            AccelRange::G6 => 0b010,
+            AccelRange::G8 => 0b011,
+            AccelRange::G16 => 0b100,
+        };
+        Some(reg_write(LSM303D_ADDR, LSM303D_CTRL0 + 2, bits << 3))
+    }
+
+    // From the LSM303D datasheet
+    fn accel_scale(&self, range: AccelRange) -> f32 {
+        match range {
+            AccelRange::G2 => 0.000061,
+            AccelRange::G4 => 0.000122,
+            AccelRange::G6 => 0.000183,
+            AccelRange::G8 => 0.000244,
+            AccelRange::G16 => 0.000732,
+        }
+    }
+
+    fn sensor_regs(&self, sensor: Sensor) -> SensorRegs {
+        match sensor {
+            Sensor::Accelerometer => SensorRegs {
+                addr: LSM303D_ADDR,
+                status_reg: 0x27,
+                ready_mask: 0x08,
+                out_reg: 0x28 | 0x80,
+            },
+            Sensor::Magnetometer => SensorRegs {
+                addr: LSM303D_ADDR,
+                status_reg: 0x07,
+                ready_mask: 0x08,
+                out_reg: 0x08 | 0x80,
+            },
+        }
+    }
+
+    fn self_tests(&self) -> &'static [SelfTest] {
+        // The magnetometer doesn't have a self-test.  The datasheet only gives a typical value for
+        // the accelerometer (at +/- 2g), so this is a generous window around it: 70-1700mg.
+        &[SelfTest {
+            name: "accel self-test",
+            sensor: Sensor::Accelerometer,
+            setup: &[reg_write(LSM303D_ADDR, LSM303D_CTRL0 + 2, 0x00)],
+            enable: &[reg_write(LSM303D_ADDR, LSM303D_CTRL0 + 2, 0x02)],
+            disable: &[reg_write(LSM303D_ADDR, LSM303D_CTRL0 + 2, 0x00)],
+            limits: [(1148.0, 27869.0), (1148.0, 27869.0), (1148.0, 27869.0)],
+        }]
+    }
+}
+
+// MinIMU-9 v5 and AltIMU-10 v5: the accelerometer is in the LSM6DS33, the magnetometer is a
+// separate LIS3MDL
+pub struct Lsm6ds33Lis3mdl;
+
+const LSM6DS33_ADDR: u8 = 0b1101011;
+const LIS3MDL_ADDR: u8 = 0b0011110;
+
+impl ImuChip for Lsm6ds33Lis3mdl {
+    fn name(&self) -> &'static str {
+        "LSM6DS33+LIS3MDL"
+    }
+
+    fn probes(&self) -> &'static [Probe] {
+        &[
+            Probe {
+                addr: LSM6DS33_ADDR,
+                reg: 0x0f, // WHO_AM_I
+                expected: 0x69,
+            },
+            Probe {
+                addr: LIS3MDL_ADDR,
+                reg: 0x0f, // WHO_AM_I
+                expected: 0x3d,
+            },
+        ]
+    }
+
+    fn config(&self) -> &'static [RegWrite] {
+        &[
+            // LSM6DS33 CTRL3_C: register auto-increment (the default, but make sure)
+            reg_write(LSM6DS33_ADDR, 0x12, 0x04),
+            // CTRL5_C: self-test off, in case a self-test was cut short
+            reg_write(LSM6DS33_ADDR, 0x14, 0x00),
+            // LIS3MDL CTRL_REG1: ultra-high-performance x/y, 80Hz output data rate
+            reg_write(LIS3MDL_ADDR, 0x20, 0x7c),
+            // CTRL_REG2: +/- 4 gauss range
+            reg_write(LIS3MDL_ADDR, 0x21, 0x00),
+            // CTRL_REG3: continuous-conversion mode
+            reg_write(LIS3MDL_ADDR, 0x22, 0x00),
+            // CTRL_REG4: ultra-high-performance z
+            reg_write(LIS3MDL_ADDR, 0x23, 0x0c),
+        ]
+    }
+
+    fn accel_range_write(&self, range: AccelRange) -> Option<RegWrite> {
+        // CTRL1_XL: 0011 -> 52Hz output data rate, then the FS bits
+        let bits = match range {
+            AccelRange::G2 => 0b00,
+            AccelRange::G4 => 0b10,
+            AccelRange::G8 => 0b11,
+            AccelRange::G16 => 0b01,
+            AccelRange::G6 => return None,
+        };
+        Some(reg_write(LSM6DS33_ADDR, 0x10, 0x30 | bits << 2))
+    }
+
+    // From the LSM6DS33 datasheet
+    fn accel_scale(&self, range: AccelRange) -> f32 {
+        match range {
+            AccelRange::G2 => 0.000061,
+            AccelRange::G4 => 0.000122,
+            AccelRange::G6 => 0.0, // unsupported
+            AccelRange::G8 => 0.000244,
+            AccelRange::G16 => 0.000488,
+        }
+    }
+
+    fn sensor_regs(&self, sensor: Sensor) -> SensorRegs {
+        match sensor {
+            Sensor::Accelerometer => SensorRegs {
+                addr: LSM6DS33_ADDR,
+                status_reg: 0x1e,
+                ready_mask: 0x01,
+                out_reg: 0x28, // auto-increment is controlled by CTRL3_C, not the address
+            },
+            Sensor::Magnetometer => SensorRegs {
+                addr: LIS3MDL_ADDR,
+                status_reg: 0x27,
+                ready_mask: 0x08,
+                out_reg: 0x28 | 0x80,
+            },
+        }
+    }
+
+    fn self_tests(&self) -> &'static [SelfTest] {
+        &[
+            // At +/- 2g, the change has to be 90-1700mg
+            SelfTest {
+                name: "accel self-test",
+                sensor: Sensor::Accelerometer,
+                setup: &[reg_write(LSM6DS33_ADDR, 0x10, 0x30)],
+                enable: &[reg_write(LSM6DS33_ADDR, 0x14, 0x01)], // CTRL5_C: positive self-test
+                disable: &[reg_write(LSM6DS33_ADDR, 0x14, 0x00)],
+                limits: [(1475.0, 27869.0), (1475.0, 27869.0), (1475.0, 27869.0)],
+            },
+            // At +/- 12 gauss, the change has to be 1-3 gauss on x and y and 0.1-1 gauss on z
+            SelfTest {
+                name: "mag self-test",
+                sensor: Sensor::Magnetometer,
+                setup: &[reg_write(LIS3MDL_ADDR, 0x21, 0x40)],
+                enable: &[reg_write(LIS3MDL_ADDR, 0x20, 0x7d)],
+                disable: &[reg_write(LIS3MDL_ADDR, 0x20, 0x7c)],
+                limits: [(2281.0, 6843.0), (2281.0, 6843.0), (228.0, 2281.0)],
+            },
+        ]
+    }
+}
+
+// MinIMU-9 v2 and older Zumo shields: separate accelerometer and magnetometer addresses in one
+// package, with a magnetometer that's big-endian and has its axes in a funny order
+pub struct Lsm303dlhc;
+
+const LSM303DLHC_ACC_ADDR: u8 = 0b0011001;
+const LSM303DLHC_MAG_ADDR: u8 = 0b0011110;
+
+impl ImuChip for Lsm303dlhc {
+    fn name(&self) -> &'static str {
+        "LSM303DLHC"
+    }
+
+    fn probes(&self) -> &'static [Probe] {
+        &[Probe {
+            addr: LSM303DLHC_MAG_ADDR,
+            reg: 0x0a, // IRA_REG_M
+            expected: 0x48,
+        }]
+    }
+
+    fn config(&self) -> &'static [RegWrite] {
+        &[
+            // CTRL_REG1_A: 50Hz output data rate, all axes enabled
+            reg_write(LSM303DLHC_ACC_ADDR, 0x20, 0x47),
+            // CRA_REG_M: 75Hz output data rate
+            reg_write(LSM303DLHC_MAG_ADDR, 0x00, 0x18),
+            // CRB_REG_M: +/- 4 gauss range
+            reg_write(LSM303DLHC_MAG_ADDR, 0x01, 0x80),
+            // MR_REG_M: continuous-conversion mode
+            reg_write(LSM303DLHC_MAG_ADDR, 0x02, 0x00),
+        ]
+    }
+
+    fn accel_range_write(&self, range: AccelRange) -> Option<RegWrite> {
+        // CTRL_REG4_A: the FS bits, plus high-resolution mode
+        let bits = match range {
+            AccelRange::G2 => 0b00,
+            AccelRange::G4 => 0b01,
+            AccelRange::G8 => 0b10,
+            AccelRange::G16 => 0b11,
+            AccelRange::G6 => return None,
+        };
+        Some(reg_write(LSM303DLHC_ACC_ADDR, 0x23, bits << 4 | 0x08))
+    }
+
+    // The 12-bit readings are left-justified, so the datasheet's mg/LSB gets divided by 16
+    fn accel_scale(&self, range: AccelRange) -> f32 {
+        match range {
+            AccelRange::G2 => 0.001 / 16.0,
+            AccelRange::G4 => 0.002 / 16.0,
+            AccelRange::G6 => 0.0, // unsupported
+            AccelRange::G8 => 0.004 / 16.0,
+            AccelRange::G16 => 0.012 / 16.0,
+        }
+    }
+
+    fn sensor_regs(&self, sensor: Sensor) -> SensorRegs {
+        match sensor {
+            Sensor::Accelerometer => SensorRegs {
+                addr: LSM303DLHC_ACC_ADDR,
+                status_reg: 0x27,
+                ready_mask: 0x08,
+                out_reg: 0x28 | 0x80,
+            },
+            Sensor::Magnetometer => SensorRegs {
+                addr: LSM303DLHC_MAG_ADDR,
+                status_reg: 0x09,
+                ready_mask: 0x01,
+                out_reg: 0x03, // the magnetometer always auto-increments
+            },
+        }
+    }
+
+    // Neither sensor has a self-test
+    fn self_tests(&self) -> &'static [SelfTest] {
+        &[]
+    }
+
+    fn decode(&self, sensor: Sensor, data: &[u8; 6]) -> (i16, i16, i16) {
+        match sensor {
+            Sensor::Accelerometer => little_endian_xyz(data),
+            // X, Z, Y, high byte first
+            Sensor::Magnetometer => (
+                ((data[0] as u16) << 8 | data[1] as u16) as i16,
+                ((data[4] as u16) << 8 | data[5] as u16) as i16,
+                ((data[2] as u16) << 8 | data[3] as u16) as i16,
+            ),
+        }
+    }
+}
@@ -0,0 +1,36 @@
+// Analog (QTR-A style) backend for the IR array.  Each sensor's output voltage goes up the less
+// light is reflected, so the readings line up with the RC-timing backend (larger is darker)
+// without any flipping.  Each sensor is converted a few times and averaged to knock down noise.
+use crate::uno::adc::{
+    Adc,
+    MAX_ADC_VALUE,
+};
+
+const SAMPLES_PER_SENSOR: u16 = 4;
+pub const MAX_READ_VALUE: u16 = MAX_ADC_VALUE;
+
+pub struct AnalogBackend<const N: usize> {
+    adc: &'static Adc,
+    channels: [u8; N],
+}
+
+impl<const N: usize> AnalogBackend<N> {
+    pub fn new(adc: &'static Adc, channels: [u8; N]) -> AnalogBackend<N> {
+        for &channel in channels.iter() {
+            adc.disable_digital_input(channel);
+        }
+        AnalogBackend { adc, channels }
+    }
+
+    pub async fn read(&self) -> [u16; N] {
+        let mut values = [0; N];
+        for i in 0..N {
+            let mut sum = 0;
+            for _ in 0..SAMPLES_PER_SENSOR {
+                sum += self.adc.read(self.channels[i]).await;
+            }
+            values[i] = sum / SAMPLES_PER_SENSOR;
+        }
+        values
+    }
+}
@@ -0,0 +1,172 @@
+// RC-timing (QTR-RC style) backend for the IR array.  Each sensor is an IR LED and phototransistor
+// pair sharing a capacitor: we charge the capacitor, then time how long it takes the
+// phototransistor to discharge it, which is shorter the more light is reflected back.  The
+// pin-change interrupts record when each sensor's pin goes low.
+//
+// The pin-change masks and ISR dispatch are worked out from the pin list.  The ISRs can only get
+// at statics, so there can be just one array, of at most MAX_SENSORS sensors.
+use crate::{
+    avr_async::Waiter,
+    uno::{
+        ir_sensors::{
+            IRPin,
+            Port,
+        },
+        timers,
+    },
+    util::*,
+};
+use avr_hal_generic::{
+    avr_device,
+    avr_device::interrupt::free as critical_section,
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+};
+
+const MAX_SENSORS: usize = 8; // one bit each in SENSOR_TRIGGERED
+const SENSOR_CHARGE_TIME_US: u16 = 10;
+const SENSOR_TIMEOUT_MS: u32 = 2;
+pub const MAX_READ_VALUE: u16 = 1000 * SENSOR_TIMEOUT_MS as u16;
+
+static mut SENSOR_PINS: [Option<IRPin>; MAX_SENSORS] = [None; MAX_SENSORS];
+static mut SENSOR_TRIGGERED: u8 = 0; // Each bit tracks whether the corresponding sensor has registered
+static mut SENSOR_VALUES: [u16; MAX_SENSORS] = [u16::MAX; MAX_SENSORS];
+static mut ALL_TRIGGERED: u8 = 0;
+static mut PC_INTERRUPTS: u8 = 0; // PCICR bits for the ports the sensors are on
+static mut IR_WAKER: Option<Waker> = None;
+
+pub struct RcBackend<const N: usize> {
+    pins: [IRPin; N],
+}
+
+impl<const N: usize> RcBackend<N> {
+    // This underflows, which fails the build when `new` is instantiated, if there are more than
+    // MAX_SENSORS pins
+    const SPARE_SENSORS: usize = MAX_SENSORS - N;
+
+    pub fn new(pins: [IRPin; N]) -> RcBackend<N> {
+        let _ = Self::SPARE_SENSORS;
+        unsafe {
+            *PCICR = 0x00;
+            *PCMSK0 = 0x00;
+            *PCMSK1 = 0x00;
+            *PCMSK2 = 0x00;
+            for (i, &pin) in pins.iter().enumerate() {
+                *pin.pc_mask_register() |= pin.mask();
+                PC_INTERRUPTS |= 1 << pin.pc_interrupt_group();
+                SENSOR_PINS[i] = Some(pin);
+            }
+            ALL_TRIGGERED = ((1u16 << N) - 1) as u8;
+        }
+        RcBackend { pins }
+    }
+
+    pub async fn read(&self) -> [u16; N] {
+        for &pin in self.pins.iter() {
+            pin.set_output(true);
+        }
+
+        arduino_uno::delay_us(SENSOR_CHARGE_TIME_US);
+        let start_time = timers::micros() as u16; // modular arithemtic makes this work even when it rolls over
+        unsafe {
+            SENSOR_VALUES = [start_time; MAX_SENSORS];
+            SENSOR_TRIGGERED = 0;
+            *PCICR = PC_INTERRUPTS;
+        }
+
+        for &pin in self.pins.iter() {
+            pin.set_floating_input();
+        }
+
+        // On a bright surface everything discharges well before the timeout
+        Discharge {
+            timeout: Waiter::new(SENSOR_TIMEOUT_MS),
+        }
+        .await;
+        unsafe { *PCICR = 0x00 };
+
+        // Anything that still hasn't fired at this point probably
+        // isn't going to, so we just write in a dummy value.
+        let mut values = [0; N];
+        unsafe {
+            for i in 0..N {
+                if SENSOR_VALUES[i] == start_time || SENSOR_VALUES[i] > MAX_READ_VALUE {
+                    SENSOR_VALUES[i] = MAX_READ_VALUE;
+                }
+                values[i] = SENSOR_VALUES[i];
+            }
+        }
+        values
+    }
+}
+
+// Resolves once every sensor has discharged, or the timeout passes
+struct Discharge {
+    timeout: Waiter,
+}
+
+impl Future for Discharge {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let done = critical_section(|_| unsafe {
+            if SENSOR_TRIGGERED == ALL_TRIGGERED {
+                return true;
+            }
+            // Same as with the TWI interrupt, the check and storing the waker have to happen
+            // together or the last sensor could fire in between
+            IR_WAKER = Some(ctx.waker().clone());
+            false
+        });
+        if done {
+            return Poll::Ready(());
+        }
+        Pin::new(&mut self.timeout).poll(ctx)
+    }
+}
+
+unsafe fn update_sensor(i: usize, is_low: bool, end_time: u16) {
+    let sensor_triggered = SENSOR_TRIGGERED & (1 << i) > 0;
+    if !sensor_triggered && is_low {
+        SENSOR_VALUES[i] = end_time - SENSOR_VALUES[i];
+        SENSOR_TRIGGERED |= 1 << i;
+        if SENSOR_TRIGGERED == ALL_TRIGGERED {
+            if let Some(waker) = IR_WAKER.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+// Check every sensor on the port whose pin-change interrupt fired
+unsafe fn update_port(port: Port) {
+    let end_time = timers::micros_no_interrupt() as u16;
+    for i in 0..MAX_SENSORS {
+        match SENSOR_PINS[i] {
+            Some(pin) if pin.port() == port => update_sensor(i, pin.is_low(), end_time),
+            _ => (),
+        }
+    }
+}
+
+#[avr_device::interrupt(atmega328p)]
+unsafe fn PCINT0() {
+    update_port(Port::B);
+}
+
+#[avr_device::interrupt(atmega328p)]
+unsafe fn PCINT1() {
+    update_port(Port::C);
+}
+
+#[avr_device::interrupt(atmega328p)]
+unsafe fn PCINT2() {
+    update_port(Port::D);
+}
@@ -1,108 +1,290 @@
+// Driver for the reflectance sensor array, generic over the number of sensors.  The backend that
+// actually reads the sensors is picked at build time: RC-timing sensors (ir_rc.rs) by default, or
+// analog ones read through the ADC (ir_analog.rs) with the "analog-ir" feature.  Either way the
+// raw readings get larger the less light is reflected, so calibration and everything built on top
+// of it works the same.
 use crate::{
     avr_async::Waiter,
-    uno::timers,
+    mem::Allocator,
+    params::{
+        self,
+        Param,
+    },
+    uno::{
+        edge::{
+            EdgeConfig,
+            EdgeDetector,
+            EdgeEvent,
+            EdgeKind,
+        },
+        line_tracker::LineTracker,
+        timers,
+    },
     util::*,
     Uno,
 };
-use arduino_uno::{
-    hal::port::{
-        mode::*,
-        portb::*,
-        portc::*,
-        portd::*,
+
+#[cfg(feature = "analog-ir")]
+pub use crate::uno::ir_analog::{
+    AnalogBackend as IRBackend,
+    MAX_READ_VALUE as MAX_SENSOR_READ_VALUE,
+};
+#[cfg(not(feature = "analog-ir"))]
+pub use crate::uno::ir_rc::{
+    RcBackend as IRBackend,
+    MAX_READ_VALUE as MAX_SENSOR_READ_VALUE,
+};
+pub use crate::uno::line_tracker::{
+    LineColor,
+    LineReading,
+    MAX_CALIBRATED_VALUE,
+};
+use core::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    future::Future,
+    pin::Pin,
+    ptr::{
+        read_volatile,
+        write_volatile,
+    },
+    task::{
+        Context,
+        Poll,
+        Waker,
     },
-    pac::EXINT as ExternalInterruptRegister,
-    prelude::*,
-    DDR,
 };
-use avr_hal_generic::avr_device;
-use embedded_hal::digital::v2::InputPin;
-use void::ResultVoidExt;
 
 const CALIBRATION_ITERS: u8 = 10;
-const SENSOR_CHARGE_TIME_US: u16 = 10;
-const SENSOR_TIMEOUT_MS: u32 = 2;
-const MAX_SENSOR_READ_VALUE: u16 = 1000 * SENSOR_TIMEOUT_MS as u16;
-pub const MAX_CALIBRATED_VALUE: u16 = 1000;
-
-static mut SENSOR_TRIGGERED: u8 = 0; // Each bit tracks whether the corresponding sensor has registered
-static mut SENSOR_VALUES: [u16; 6] = [u16::MAX; 6];
-
-type S0 = PD5<Input<Floating>>;
-type S1 = PC2<Input<Floating>>;
-type S2 = PC0<Input<Floating>>;
-type S3 = PB3<Input<Floating>>;
-type S4 = PC3<Input<Floating>>;
-type S5 = PD4<Input<Floating>>;
-
-pub struct IRSensors {
-    s0: Option<S0>,
-    s1: Option<S1>,
-    s2: Option<S2>,
-    s3: Option<S3>,
-    s4: Option<S4>,
-    s5: Option<S5>,
-    calibration_vector: [(i16, f32); 6],
-    pub values: &'static [u16; 6],
+const EMITTER_SETTLE_TIME_US: u16 = 200;
+
+// We store a separate IR calibration for each surface we run on
+pub const PROFILE_COUNT: usize = 4;
+pub const PROFILE_NAMES: [&str; PROFILE_COUNT] = ["table", "mat", "spare0", "spare1"];
+pub const MAX_IR_SENSORS: usize = 8; // the EEPROM leaves room for profiles this wide
+
+pub type IRCalibrationVector<const N: usize> = [(u16, u16); N]; // (min, max) for each sensor
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Port {
+    B,
+    C,
+    D,
 }
 
-impl IRSensors {
-    pub fn new(s0: S0, s1: S1, s2: S2, s3: S3, s4: S4, s5: S5) -> IRSensors {
+// A pin on the Uno, e.g. `IRPin::new(Port::D, 5)` for digital pin 5
+#[derive(Clone, Copy)]
+pub struct IRPin {
+    port: Port,
+    bit: u8,
+}
+
+impl IRPin {
+    pub const fn new(port: Port, bit: u8) -> IRPin {
+        IRPin { port, bit }
+    }
+
+    pub fn port(self) -> Port {
+        self.port
+    }
+
+    pub fn mask(self) -> u8 {
+        1 << self.bit
+    }
+
+    // PORTB is on PCINT0-7, PORTC on PCINT8-14, and PORTD on PCINT16-23
+    pub fn pc_interrupt_group(self) -> u8 {
+        match self.port {
+            Port::B => 0,
+            Port::C => 1,
+            Port::D => 2,
+        }
+    }
+
+    pub fn pc_mask_register(self) -> *mut u8 {
+        match self.port {
+            Port::B => PCMSK0,
+            Port::C => PCMSK1,
+            Port::D => PCMSK2,
+        }
+    }
+
+    // PINx, DDRx and PORTx are laid out one after the other for each port
+    pub fn pin_register(self) -> *const u8 {
+        match self.port {
+            Port::B => PINB,
+            Port::C => PINC,
+            Port::D => PIND,
+        }
+    }
+
+    pub fn ddr_register(self) -> *mut u8 {
+        unsafe { self.pin_register().add(1) as *mut u8 }
+    }
+
+    pub fn port_register(self) -> *mut u8 {
+        unsafe { self.pin_register().add(2) as *mut u8 }
+    }
+
+    pub fn is_low(self) -> bool {
+        unsafe { read_volatile(self.pin_register()) & self.mask() == 0 }
+    }
+
+    pub fn set_output(self, high: bool) {
+        unsafe {
+            set_bits(self.port_register(), self.mask(), high);
+            set_bits(self.ddr_register(), self.mask(), true);
+        }
+    }
+
+    pub fn set_floating_input(self) {
+        // Stop driving the pin before turning off its output, or we'd discharge the sensor
         unsafe {
-            *PCICR = 0x00;
-            *PCMSK0 = 0x08;
-            *PCMSK1 = 0x0d;
-            *PCMSK2 = 0x30;
-        }
-        IRSensors {
-            s0: Some(s0),
-            s1: Some(s1),
-            s2: Some(s2),
-            s3: Some(s3),
-            s4: Some(s4),
-            s5: Some(s5),
-            calibration_vector: [(0, MAX_CALIBRATED_VALUE as f32); 6],
-            values: unsafe { &SENSOR_VALUES },
-        }
-    }
-
-    pub fn set_calibration_vector(&mut self, vector: (u16, u16, u16, u16, u16, u16, u16, u16, u16, u16, u16, u16)) {
-        self.calibration_vector = [
-            (
-                vector.0 as i16,
-                MAX_CALIBRATED_VALUE as f32 / ((vector.1 - vector.0) as f32),
-            ),
-            (
-                vector.2 as i16,
-                MAX_CALIBRATED_VALUE as f32 / ((vector.3 - vector.2) as f32),
-            ),
-            (
-                vector.4 as i16,
-                MAX_CALIBRATED_VALUE as f32 / ((vector.5 - vector.4) as f32),
-            ),
-            (
-                vector.6 as i16,
-                MAX_CALIBRATED_VALUE as f32 / ((vector.7 - vector.6) as f32),
-            ),
-            (
-                vector.8 as i16,
-                MAX_CALIBRATED_VALUE as f32 / ((vector.9 - vector.8) as f32),
-            ),
-            (
-                vector.10 as i16,
-                MAX_CALIBRATED_VALUE as f32 / ((vector.11 - vector.10) as f32),
-            ),
-        ];
-    }
-
-    pub async fn calibrate(&mut self, ddr: &mut DDR, dark: bool) -> [u16; 6] {
-        let mut extreme_values: [u16; 6] = if dark { [0; 6] } else { [MAX_SENSOR_READ_VALUE; 6] };
+            set_bits(self.ddr_register(), self.mask(), false);
+            set_bits(self.port_register(), self.mask(), false);
+        }
+    }
+}
+
+unsafe fn set_bits(register: *mut u8, mask: u8, on: bool) {
+    let value = read_volatile(register);
+    write_volatile(register, if on { value | mask } else { value & !mask });
+}
+
+// Tracks the lightest and darkest reading from each sensor while the robot sweeps back and forth
+// across an edge, so every sensor sees both surfaces without anyone having to move the robot by
+// hand
+pub struct CalibrationSweep<const N: usize> {
+    min_values: [u16; N],
+    max_values: [u16; N],
+}
+
+impl<const N: usize> CalibrationSweep<N> {
+    pub fn new() -> CalibrationSweep<N> {
+        CalibrationSweep {
+            min_values: [MAX_SENSOR_READ_VALUE; N],
+            max_values: [0; N],
+        }
+    }
+
+    pub fn update(&mut self, values: &[u16; N]) {
+        for (i, &value) in values.iter().enumerate() {
+            self.min_values[i] = self.min_values[i].min(value);
+            self.max_values[i] = self.max_values[i].max(value);
+        }
+    }
+
+    // A sensor that never crossed the edge (or can't tell the two surfaces apart) would give us a
+    // calibration that's all noise
+    pub fn has_contrast(&self, sensor: usize, min_contrast: u16) -> bool {
+        self.max_values[sensor].saturating_sub(self.min_values[sensor]) >= min_contrast
+    }
+
+    pub fn calibration_vector(&self) -> IRCalibrationVector<N> {
+        let mut vector = [(0, 0); N];
+        for i in 0..N {
+            vector[i] = (self.min_values[i], self.max_values[i]);
+        }
+        vector
+    }
+}
+
+impl<const N: usize> EdgeConfig<N> {
+    // Every sensor gets the same threshold for now, since they're all calibrated to the same scale
+    pub fn from_params() -> EdgeConfig<N> {
+        EdgeConfig {
+            thresholds: [params::get_u32(Param::IREdgeThreshold) as u16; N],
+            hysteresis: params::get_u32(Param::IREdgeHysteresis) as u16,
+            debounce_reads: params::get_u32(Param::IREdgeDebounce) as u8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum IRReadMode {
+    EmittersOn,
+    // Takes a second read with the emitters off and takes out whatever the room light contributed
+    AmbientCompensated,
+}
+
+impl IRReadMode {
+    pub fn from_params() -> IRReadMode {
+        if params::get_u32(Param::IRAmbientCompensation) != 0 {
+            IRReadMode::AmbientCompensated
+        } else {
+            IRReadMode::EmittersOn
+        }
+    }
+}
+
+// The latest sample from the IR driver.  `sequence` goes up by one with every sample, starting at
+// 1; 0 means nothing has been sampled yet.
+#[derive(Clone, Copy)]
+pub struct IRSnapshot<const N: usize> {
+    pub mode: IRReadMode,
+    pub raw: [u16; N],
+    pub calibrated: [u16; N],
+    pub timestamp_ms: u32,
+    pub sequence: u32,
+}
+
+pub struct IRSensors<const N: usize> {
+    backend: IRBackend<N>,
+    emitter: Option<IRPin>, // has to be jumpered to the array's LEDON pin for ambient compensation
+    calibration_vector: Cell<[(i16, f32); N]>,
+    snapshot: Cell<IRSnapshot<N>>,
+    mode: Cell<IRReadMode>, // whatever the last caller asked for
+    waker: RefCell<Option<Waker>>,
+    line_tracker: RefCell<LineTracker<N>>,
+    edge_detector: RefCell<EdgeDetector<N>>,
+    edge_event: Cell<Option<EdgeEvent>>,
+}
+
+impl<const N: usize> IRSensors<N> {
+    // Like the MotorController, this is a static reference because the driver holds onto "self"
+    pub fn new(backend: IRBackend<N>, emitter: Option<IRPin>) -> &'static IRSensors<N> {
+        if let Some(emitter) = emitter {
+            emitter.set_output(true);
+        }
+
+        Allocator::get().new(IRSensors {
+            backend,
+            emitter,
+            calibration_vector: Cell::new([(0, MAX_CALIBRATED_VALUE as f32); N]),
+            snapshot: Cell::new(IRSnapshot {
+                mode: IRReadMode::EmittersOn,
+                raw: [MAX_SENSOR_READ_VALUE; N],
+                calibrated: [MAX_CALIBRATED_VALUE; N],
+                timestamp_ms: 0,
+                sequence: 0,
+            }),
+            mode: Cell::new(IRReadMode::EmittersOn),
+            waker: RefCell::new(None),
+            line_tracker: RefCell::new(LineTracker::new()),
+            edge_detector: RefCell::new(EdgeDetector::new()),
+            edge_event: Cell::new(None),
+        })
+    }
+
+    pub fn set_calibration_vector(&self, vector: IRCalibrationVector<N>) {
+        let mut calibration_vector = [(0, 0.0); N];
+        for (i, &(min, max)) in vector.iter().enumerate() {
+            calibration_vector[i] = (min as i16, MAX_CALIBRATED_VALUE as f32 / ((max - min) as f32));
+        }
+        self.calibration_vector.set(calibration_vector);
+    }
+
+    // Use the same mode here as for the reads the calibration is meant for
+    pub async fn calibrate(&self, dark: bool, mode: IRReadMode) -> [u16; N] {
+        let mut extreme_values: [u16; N] = if dark { [0; N] } else { [MAX_SENSOR_READ_VALUE; N] };
         for _ in 0..CALIBRATION_ITERS {
-            self.read(ddr).await;
+            let values = self.next_snapshot(mode).await.raw;
 
-            for j in 0..self.values.len() {
-                if (dark && self.values[j] > extreme_values[j]) || (!dark && self.values[j] < extreme_values[j]) {
-                    extreme_values[j] = self.values[j];
+            for j in 0..values.len() {
+                if (dark && values[j] > extreme_values[j]) || (!dark && values[j] < extreme_values[j]) {
+                    extreme_values[j] = values[j];
                 }
             }
         }
@@ -110,107 +292,141 @@ impl IRSensors {
         extreme_values
     }
 
-    pub async fn read_calibrated(&mut self, ddr: &mut DDR) {
-        self.read(ddr).await;
-        unsafe {
-            for i in 0..SENSOR_VALUES.len() {
-                let v = (SENSOR_VALUES[i] as i16 - self.calibration_vector[i].0) as f32 * self.calibration_vector[i].1;
-                SENSOR_VALUES[i] = match v {
-                    v if v < 0.0 => 0,
-                    v if v > MAX_CALIBRATED_VALUE as f32 => MAX_CALIBRATED_VALUE,
-                    v => v as u16,
-                }
-            }
+    // Feeds every sample for the next `duration_ms` into `sweep`; it's up to the caller to get the
+    // robot moving
+    pub async fn sweep(&self, sweep: &mut CalibrationSweep<N>, duration_ms: u32, mode: IRReadMode) {
+        let start_ms = timers::millis();
+        while timers::millis().wrapping_sub(start_ms) < duration_ms {
+            sweep.update(&self.next_snapshot(mode).await.raw);
         }
     }
 
-    pub async fn read(&mut self, ddr: &mut DDR) {
-        let s0 = self.s0.take().unwrap();
-        let s1 = self.s1.take().unwrap();
-        let s2 = self.s2.take().unwrap();
-        let s3 = self.s3.take().unwrap();
-        let s4 = self.s4.take().unwrap();
-        let s5 = self.s5.take().unwrap();
+    // The last sample the driver took, without waiting
+    pub fn latest(&self) -> IRSnapshot<N> {
+        self.snapshot.get()
+    }
 
-        let mut s0 = s0.into_output(ddr);
-        let mut s1 = s1.into_output(ddr);
-        let mut s2 = s2.into_output(ddr);
-        let mut s3 = s3.into_output(ddr);
-        let mut s4 = s4.into_output(ddr);
-        let mut s5 = s5.into_output(ddr);
+    // Have the driver sample in `mode` from now on, without waiting for a sample
+    pub fn set_mode(&self, mode: IRReadMode) {
+        self.mode.set(mode);
+    }
 
-        s0.set_high().void_unwrap();
-        s1.set_high().void_unwrap();
-        s2.set_high().void_unwrap();
-        s3.set_high().void_unwrap();
-        s4.set_high().void_unwrap();
-        s5.set_high().void_unwrap();
+    // Waits for a sample taken in `mode` after this is called.  The driver keeps sampling in that
+    // mode until someone asks for a different one.
+    pub async fn next_snapshot(&self, mode: IRReadMode) -> IRSnapshot<N> {
+        self.mode.set(mode);
+        FreshSnapshot {
+            sensors: self,
+            sequence: self.snapshot.get().sequence,
+            mode,
+        }
+        .await
+    }
 
-        arduino_uno::delay_us(SENSOR_CHARGE_TIME_US);
-        let start_time = timers::micros() as u16; // modular arithemtic makes this work even when it rolls over
-        unsafe {
-            SENSOR_VALUES = [start_time; 6];
-            SENSOR_TRIGGERED = 0;
-        }
-
-        toggle_pc_interrupts();
-        let s0 = s0.into_floating_input(ddr);
-        let s1 = s1.into_floating_input(ddr);
-        let s2 = s2.into_floating_input(ddr);
-        let s3 = s3.into_floating_input(ddr);
-        let s4 = s4.into_floating_input(ddr);
-        let s5 = s5.into_floating_input(ddr);
-
-        self.s0 = Some(s0);
-        self.s1 = Some(s1);
-        self.s2 = Some(s2);
-        self.s3 = Some(s3);
-        self.s4 = Some(s4);
-        self.s5 = Some(s5);
-
-        Waiter::new(SENSOR_TIMEOUT_MS).await;
-        toggle_pc_interrupts();
-
-        // Anything that still hasn't fired at this point probably
-        // isn't going to, so we just write in a dummy value.
-        unsafe {
-            for i in 0..SENSOR_VALUES.len() {
-                if SENSOR_VALUES[i] == start_time || SENSOR_VALUES[i] > MAX_SENSOR_READ_VALUE {
-                    SENSOR_VALUES[i] = MAX_SENSOR_READ_VALUE;
-                }
+    // Needs a calibration vector to have been set, since the raw readings aren't comparable across
+    // sensors
+    pub async fn read_line(&self, color: LineColor, mode: IRReadMode) -> LineReading {
+        let snapshot = self.next_snapshot(mode).await;
+        self.line_tracker.borrow_mut().update(&snapshot.calibrated, color)
+    }
+
+    // The most recent change in the debounced edge state that nobody has looked at yet; `edge()` has
+    // the state itself
+    pub fn take_edge_event(&self) -> Option<EdgeEvent> {
+        self.edge_event.take()
+    }
+
+    pub fn edge(&self) -> EdgeKind {
+        self.edge_detector.borrow().current()
+    }
+
+    pub fn get_ir_driver(&'static self) -> &'static mut dyn Future<Output = !> {
+        let future = async move || loop {
+            let mode = self.mode.get();
+            let raw = self.read_in_mode(mode).await;
+            let previous = self.snapshot.get();
+            let snapshot = IRSnapshot {
+                mode,
+                raw,
+                calibrated: self.calibrate_values(&raw),
+                timestamp_ms: timers::millis(),
+                sequence: previous.sequence.wrapping_add(1).max(1),
+            };
+            self.snapshot.set(snapshot);
+
+            // Every snapshot goes through the edge detector so that its debouncing sees consecutive
+            // reads, whether or not anyone is watching for edges right now
+            let config = EdgeConfig::from_params();
+            let event = self
+                .edge_detector
+                .borrow_mut()
+                .update(snapshot.timestamp_ms, &snapshot.calibrated, &config);
+            if event.is_some() {
+                self.edge_event.set(event);
+            }
+
+            if let Some(waker) = self.waker.borrow_mut().take() {
+                waker.wake();
+            }
+
+            Waiter::new(params::get_u32(Param::IRSampleDelayMs)).await;
+        };
+        Allocator::get().new(future())
+    }
+
+    fn calibrate_values(&self, raw: &[u16; N]) -> [u16; N] {
+        let calibration_vector = self.calibration_vector.get();
+        let mut values = [0; N];
+        for i in 0..values.len() {
+            let v = (raw[i] as i16 - calibration_vector[i].0) as f32 * calibration_vector[i].1;
+            values[i] = match v {
+                v if v < 0.0 => 0,
+                v if v > MAX_CALIBRATED_VALUE as f32 => MAX_CALIBRATED_VALUE,
+                v => v as u16,
             }
         }
+        values
     }
-}
 
-unsafe fn update_sensor(i: usize, is_low: bool, end_time: u16) {
-    let sensor_triggered = SENSOR_TRIGGERED & (1 << i) > 0;
-    if !sensor_triggered && is_low {
-        SENSOR_VALUES[i] = end_time - SENSOR_VALUES[i];
-        SENSOR_TRIGGERED |= 1 << i;
+    // Only the IR driver reads the sensors, so there's never more than one read going at a time
+    async fn read_in_mode(&self, mode: IRReadMode) -> [u16; N] {
+        let lit = self.backend.read().await;
+        let emitter = match (mode, self.emitter) {
+            (IRReadMode::AmbientCompensated, Some(emitter)) => emitter,
+            _ => return lit,
+        };
+
+        emitter.set_output(false);
+        arduino_uno::delay_us(EMITTER_SETTLE_TIME_US);
+        let ambient = self.backend.read().await;
+        emitter.set_output(true);
+        arduino_uno::delay_us(EMITTER_SETTLE_TIME_US);
+
+        // Room light makes the lit reading shorter by about as much as it makes the ambient reading
+        // shorter than the timeout, so we add that back in
+        let mut values = [0; N];
+        for i in 0..values.len() {
+            values[i] = (lit[i] + (MAX_SENSOR_READ_VALUE - ambient[i])).min(MAX_SENSOR_READ_VALUE);
+        }
+        values
     }
 }
 
-#[avr_device::interrupt(atmega328p)]
-unsafe fn PCINT0() {
-    let s3: S3 = get_pin();
-    let end_time = timers::micros_no_interrupt() as u16;
-    update_sensor(3, s3.is_low().void_unwrap(), end_time);
+struct FreshSnapshot<'a, const N: usize> {
+    sensors: &'a IRSensors<N>,
+    sequence: u32,
+    mode: IRReadMode,
 }
 
-#[avr_device::interrupt(atmega328p)]
-unsafe fn PCINT1() {
-    let (s1, s2, s4): (S1, S2, S4) = (get_pin(), get_pin(), get_pin());
-    let end_time = timers::micros_no_interrupt() as u16;
-    update_sensor(1, s1.is_low().void_unwrap(), end_time);
-    update_sensor(2, s2.is_low().void_unwrap(), end_time);
-    update_sensor(4, s4.is_low().void_unwrap(), end_time);
-}
+impl<'a, const N: usize> Future for FreshSnapshot<'a, N> {
+    type Output = IRSnapshot<N>;
 
-#[avr_device::interrupt(atmega328p)]
-unsafe fn PCINT2() {
-    let (s0, s5): (S0, S5) = (get_pin(), get_pin());
-    let end_time = timers::micros_no_interrupt() as u16;
-    update_sensor(0, s0.is_low().void_unwrap(), end_time);
-    update_sensor(5, s5.is_low().void_unwrap(), end_time);
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let snapshot = self.sensors.snapshot.get();
+        if snapshot.sequence != self.sequence && snapshot.mode == self.mode {
+            return Poll::Ready(snapshot);
+        }
+        *self.sensors.waker.borrow_mut() = Some(ctx.waker().clone());
+        Poll::Pending
+    }
 }
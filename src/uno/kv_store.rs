@@ -0,0 +1,592 @@
+// A small log-structured key/value store for values that change often (run counters, tuning
+// parameters, etc).  The KV region of the EEPROM is split into pages; records are appended to the
+// active page, and when it fills up the live records are compacted into the next page.  Rotating
+// through the pages spreads the erase/write cycles over the whole region.
+//
+// Page layout:
+//
+//     [magic] [sequence lo] [sequence hi] [record] [record] ... [0xff] <stale bytes>
+//
+// Record layout:
+//
+//     [key] [len] [data; len] [checksum]
+//
+// A key of 0xff marks the end of the log.  Records are written back-to-front (the terminator for
+// the *next* record first, then the body, then the key) so a write that's interrupted by a power
+// failure leaves the log exactly as it was.  Compaction only becomes visible once the new page's
+// magic byte is written, and the page with the newest sequence number wins at mount time.
+use crate::uno::eeprom_value::EepromValue;
+use core::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+pub type KvKey = u8;
+
+pub const KV_RUN_COUNT: KvKey = 0x01;
+
+pub const MAX_KV_VALUE_LEN: usize = 16;
+
+// Everything from the end of the IR profiles up to the commit marker
+pub const KV_REGION_SIZE: usize = 750;
+
+// Every live record has to fit in a single page, so the pages are sized for the whole parameter
+// registry rather than split finer for wear leveling.  With every parameter tuned away from its
+// default a page still has room for about seven more updates before it's compacted into the next
+// one, so each byte of the region sees roughly one erase/write cycle per 20 updates; compaction
+// also skips any byte that already holds the value being copied, which is most of them when the
+// same parameters keep getting tuned.
+const PAGE_COUNT: u16 = 3;
+const PAGE_SIZE: u16 = (KV_REGION_SIZE as u16) / PAGE_COUNT;
+const PAGE_MAGIC: u8 = 0x6b;
+const HEADER_SIZE: u16 = 3; // magic, sequence number (2 bytes)
+const RECORD_OVERHEAD: u16 = 3; // key, length, checksum
+const FREE_KEY: KvKey = 0xff;
+
+// Room for live records in a page
+pub const KV_PAGE_CAPACITY: usize = (PAGE_SIZE - HEADER_SIZE) as usize;
+
+// The space a value of `len` bytes takes up in a page
+pub const fn kv_record_size(len: usize) -> usize {
+    RECORD_OVERHEAD as usize + len
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KvError {
+    NotFound,
+    Full,
+    InvalidKey,
+    ValueTooLarge,
+    WrongSize,
+}
+
+// Byte-level access to whatever is backing the store; this is implemented for the real EEPROM and
+// for an in-memory model so that the store logic can be exercised on the host
+pub trait KvStorage {
+    fn poll_read_u8(&self, addr: u16, ctx: &mut Context) -> Poll<u8>;
+    fn poll_write_u8(&self, addr: u16, value: u8, ctx: &mut Context) -> Poll<()>;
+}
+
+// An EEPROM model that lives in RAM; it starts out erased (all 0xff) like a fresh chip
+pub struct MemoryStorage<const N: usize> {
+    pub bytes: RefCell<[u8; N]>,
+}
+
+impl<const N: usize> MemoryStorage<N> {
+    pub fn new() -> MemoryStorage<N> {
+        MemoryStorage {
+            bytes: RefCell::new([0xff; N]),
+        }
+    }
+}
+
+impl<const N: usize> KvStorage for MemoryStorage<N> {
+    fn poll_read_u8(&self, addr: u16, _: &mut Context) -> Poll<u8> {
+        Poll::Ready(self.bytes.borrow()[addr as usize])
+    }
+
+    fn poll_write_u8(&self, addr: u16, value: u8, _: &mut Context) -> Poll<()> {
+        self.bytes.borrow_mut()[addr as usize] = value;
+        Poll::Ready(())
+    }
+}
+
+struct ReadU8<'a, S: KvStorage> {
+    storage: &'a S,
+    addr: u16,
+}
+
+impl<'a, S: KvStorage> Future for ReadU8<'a, S> {
+    type Output = u8;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        self.storage.poll_read_u8(self.addr, ctx)
+    }
+}
+
+struct WriteU8<'a, S: KvStorage> {
+    storage: &'a S,
+    addr: u16,
+    value: u8,
+}
+
+impl<'a, S: KvStorage> Future for WriteU8<'a, S> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        self.storage.poll_write_u8(self.addr, self.value, ctx)
+    }
+}
+
+fn checksum(key: KvKey, data: &[u8]) -> u8 {
+    let mut sum = 0x5au8 ^ key ^ data.len() as u8;
+    for &b in data {
+        sum = sum.rotate_left(1) ^ b;
+    }
+    sum
+}
+
+pub struct KvStore<'a, S: KvStorage> {
+    storage: &'a S,
+    base: u16,
+    mounted: bool,
+    active_page: u16,
+    sequence: u16,
+    write_pos: u16, // offset of the end-of-log marker in the active page
+}
+
+impl<'a, S: KvStorage> KvStore<'a, S> {
+    // The region starting at `base` must be at least KV_REGION_SIZE bytes long
+    pub fn new(storage: &'a S, base: u16) -> KvStore<'a, S> {
+        KvStore {
+            storage,
+            base,
+            mounted: false,
+            active_page: 0,
+            sequence: 0,
+            write_pos: HEADER_SIZE,
+        }
+    }
+
+    pub async fn get<T: EepromValue>(&mut self, key: KvKey) -> Result<T, KvError> {
+        let mut buf = [0u8; MAX_KV_VALUE_LEN];
+        let len = self.get_bytes(key, &mut buf).await?;
+        if len != T::SIZE {
+            return Err(KvError::WrongSize);
+        }
+        Ok(T::load(&buf))
+    }
+
+    pub async fn put<T: EepromValue>(&mut self, key: KvKey, value: &T) -> Result<(), KvError> {
+        if T::SIZE > MAX_KV_VALUE_LEN {
+            return Err(KvError::ValueTooLarge);
+        }
+
+        let mut buf = [0u8; MAX_KV_VALUE_LEN];
+        value.store(&mut buf);
+        self.put_bytes(key, &buf[..T::SIZE]).await
+    }
+
+    // Copies the latest value for key into buf, and returns its length
+    pub async fn get_bytes(&mut self, key: KvKey, buf: &mut [u8]) -> Result<usize, KvError> {
+        self.mount().await;
+        let pos = self.find_latest(key).await.ok_or(KvError::NotFound)?;
+        let len = self.read(self.record_addr(pos) + 1).await as usize;
+        if len > buf.len() {
+            return Err(KvError::ValueTooLarge);
+        }
+        for i in 0..len {
+            buf[i] = self.read(self.record_addr(pos) + 2 + i as u16).await;
+        }
+        Ok(len)
+    }
+
+    pub async fn put_bytes(&mut self, key: KvKey, data: &[u8]) -> Result<(), KvError> {
+        if key == FREE_KEY {
+            return Err(KvError::InvalidKey);
+        }
+        if data.len() > MAX_KV_VALUE_LEN {
+            return Err(KvError::ValueTooLarge);
+        }
+        self.mount().await;
+
+        // Don't spend a write cycle if nothing has changed
+        let mut current = [0u8; MAX_KV_VALUE_LEN];
+        if let Ok(len) = self.get_bytes(key, &mut current).await {
+            if &current[..len] == data {
+                return Ok(());
+            }
+        }
+
+        let record_len = RECORD_OVERHEAD + data.len() as u16;
+        if self.write_pos + record_len > PAGE_SIZE {
+            // Check before compacting, since compaction drops the old value for key
+            if HEADER_SIZE + self.live_size(key).await + record_len > PAGE_SIZE {
+                return Err(KvError::Full);
+            }
+            self.compact(key, data).await;
+            return Ok(());
+        }
+
+        self.append(self.active_page, self.write_pos, key, data).await;
+        self.write_pos += record_len;
+        Ok(())
+    }
+
+    async fn mount(&mut self) {
+        if self.mounted {
+            return;
+        }
+
+        let mut found = false;
+        for page in 0..PAGE_COUNT {
+            let addr = self.page_addr(page);
+            if self.read(addr).await != PAGE_MAGIC {
+                continue;
+            }
+            let sequence = self.read(addr + 1).await as u16 | (self.read(addr + 2).await as u16) << 8;
+            if !found || (sequence.wrapping_sub(self.sequence) as i16) > 0 {
+                found = true;
+                self.active_page = page;
+                self.sequence = sequence;
+            }
+        }
+
+        if !found {
+            // Nothing's ever been written, so format the first page
+            let addr = self.page_addr(0);
+            self.write(addr + HEADER_SIZE, FREE_KEY).await;
+            self.write(addr + 1, 0).await;
+            self.write(addr + 2, 0).await;
+            self.write(addr, PAGE_MAGIC).await;
+            self.active_page = 0;
+            self.sequence = 0;
+        }
+
+        self.write_pos = self.find_end().await;
+        self.mounted = true;
+    }
+
+    // Walk the log to find the end-of-log marker; anything that doesn't look like a record (e.g.,
+    // a length that runs off the end of the page) also ends the log
+    async fn find_end(&self) -> u16 {
+        let mut pos = HEADER_SIZE;
+        while pos + RECORD_OVERHEAD <= PAGE_SIZE {
+            let addr = self.record_addr(pos);
+            if self.read(addr).await == FREE_KEY {
+                break;
+            }
+            let len = self.read(addr + 1).await as u16;
+            if len as usize > MAX_KV_VALUE_LEN || pos + RECORD_OVERHEAD + len > PAGE_SIZE {
+                break;
+            }
+            pos += RECORD_OVERHEAD + len;
+        }
+        pos
+    }
+
+    async fn find_latest(&self, key: KvKey) -> Option<u16> {
+        self.find_latest_from(key, HEADER_SIZE).await
+    }
+
+    async fn find_latest_from(&self, key: KvKey, start: u16) -> Option<u16> {
+        let mut latest = None;
+        let mut pos = start;
+        while pos < self.write_pos {
+            let len = self.read(self.record_addr(pos) + 1).await as u16;
+            if self.read(self.record_addr(pos)).await == key && self.is_valid(pos).await {
+                latest = Some(pos);
+            }
+            pos += RECORD_OVERHEAD + len;
+        }
+        latest
+    }
+
+    async fn is_valid(&self, pos: u16) -> bool {
+        let addr = self.record_addr(pos);
+        let key = self.read(addr).await;
+        let len = self.read(addr + 1).await as usize;
+
+        let mut data = [0u8; MAX_KV_VALUE_LEN];
+        for i in 0..len {
+            data[i] = self.read(addr + 2 + i as u16).await;
+        }
+        self.read(addr + 2 + len as u16).await == checksum(key, &data[..len])
+    }
+
+    // Total size of the records that would survive compaction
+    async fn live_size(&self, skip_key: KvKey) -> u16 {
+        let mut size = 0;
+        let mut pos = HEADER_SIZE;
+        while pos < self.write_pos {
+            let addr = self.record_addr(pos);
+            let key = self.read(addr).await;
+            let len = self.read(addr + 1).await as u16;
+            if key != skip_key && self.find_latest_from(key, pos).await == Some(pos) {
+                size += RECORD_OVERHEAD + len;
+            }
+            pos += RECORD_OVERHEAD + len;
+        }
+        size
+    }
+
+    // Copy the latest version of every other key into the next page, followed by the new value
+    // for `new_key`, and then make it the active page.  The new value has to land before the page
+    // is committed, or losing power in between would lose the key altogether.
+    async fn compact(&mut self, new_key: KvKey, new_data: &[u8]) {
+        let new_page = (self.active_page + 1) % PAGE_COUNT;
+        let new_addr = self.page_addr(new_page);
+        self.write(new_addr, FREE_KEY).await; // invalidate the page until we're done
+        self.write(new_addr + HEADER_SIZE, FREE_KEY).await;
+
+        let mut new_pos = HEADER_SIZE;
+        let mut pos = HEADER_SIZE;
+        while pos < self.write_pos {
+            let addr = self.record_addr(pos);
+            let key = self.read(addr).await;
+            let len = self.read(addr + 1).await as u16;
+            if key != new_key && self.find_latest_from(key, pos).await == Some(pos) {
+                let mut data = [0u8; MAX_KV_VALUE_LEN];
+                for i in 0..len {
+                    data[i as usize] = self.read(addr + 2 + i).await;
+                }
+                self.append(new_page, new_pos, key, &data[..len as usize]).await;
+                new_pos += RECORD_OVERHEAD + len;
+            }
+            pos += RECORD_OVERHEAD + len;
+        }
+        self.append(new_page, new_pos, new_key, new_data).await;
+        new_pos += RECORD_OVERHEAD + new_data.len() as u16;
+
+        let sequence = self.sequence.wrapping_add(1);
+        self.write(new_addr + 1, sequence as u8).await;
+        self.write(new_addr + 2, (sequence >> 8) as u8).await;
+        self.write(new_addr, PAGE_MAGIC).await; // commit
+
+        self.active_page = new_page;
+        self.sequence = sequence;
+        self.write_pos = new_pos;
+    }
+
+    async fn append(&self, page: u16, pos: u16, key: KvKey, data: &[u8]) {
+        let addr = self.page_addr(page) + pos;
+        let end = pos + RECORD_OVERHEAD + data.len() as u16;
+        if end < PAGE_SIZE {
+            self.write(self.page_addr(page) + end, FREE_KEY).await;
+        }
+
+        self.write(addr + 1, data.len() as u8).await;
+        for (i, &b) in data.iter().enumerate() {
+            self.write(addr + 2 + i as u16, b).await;
+        }
+        self.write(addr + 2 + data.len() as u16, checksum(key, data)).await;
+        self.write(addr, key).await; // commit
+    }
+
+    fn page_addr(&self, page: u16) -> u16 {
+        self.base + page * PAGE_SIZE
+    }
+
+    fn record_addr(&self, pos: u16) -> u16 {
+        self.page_addr(self.active_page) + pos
+    }
+
+    async fn read(&self, addr: u16) -> u8 {
+        ReadU8 {
+            storage: self.storage,
+            addr,
+        }
+        .await
+    }
+
+    async fn write(&self, addr: u16, value: u8) {
+        WriteU8 {
+            storage: self.storage,
+            addr,
+            value,
+        }
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr_async::block_on;
+    use core::cell::Cell;
+
+    type Memory = [u8; KV_REGION_SIZE];
+
+    // Counts writes, and can simulate losing power by dropping every write after the first
+    // `cut_after` of them
+    struct TestStorage {
+        memory: MemoryStorage<KV_REGION_SIZE>,
+        writes: Cell<usize>,
+        cut_after: Option<usize>,
+    }
+
+    impl TestStorage {
+        fn new(bytes: Memory, cut_after: Option<usize>) -> TestStorage {
+            let memory = MemoryStorage::new();
+            *memory.bytes.borrow_mut() = bytes;
+            TestStorage {
+                memory,
+                writes: Cell::new(0),
+                cut_after,
+            }
+        }
+
+        fn bytes(&self) -> Memory {
+            *self.memory.bytes.borrow()
+        }
+    }
+
+    impl KvStorage for TestStorage {
+        fn poll_read_u8(&self, addr: u16, ctx: &mut Context) -> Poll<u8> {
+            self.memory.poll_read_u8(addr, ctx)
+        }
+
+        fn poll_write_u8(&self, addr: u16, value: u8, ctx: &mut Context) -> Poll<()> {
+            let writes = self.writes.get();
+            self.writes.set(writes + 1);
+            if self.cut_after.is_some_and(|cut_after| writes >= cut_after) {
+                return Poll::Ready(());
+            }
+            self.memory.poll_write_u8(addr, value, ctx)
+        }
+    }
+
+    fn erased() -> Memory {
+        [0xff; KV_REGION_SIZE]
+    }
+
+    fn put<T: EepromValue>(bytes: Memory, key: KvKey, value: T) -> (Memory, Result<(), KvError>) {
+        let storage = TestStorage::new(bytes, None);
+        let result = block_on(KvStore::new(&storage, 0).put(key, &value));
+        (storage.bytes(), result)
+    }
+
+    fn get<T: EepromValue>(bytes: Memory, key: KvKey) -> Result<T, KvError> {
+        let storage = TestStorage::new(bytes, None);
+        block_on(KvStore::new(&storage, 0).get(key))
+    }
+
+    #[test]
+    fn append_and_remount() {
+        let storage = TestStorage::new(erased(), None);
+        let mut store = KvStore::new(&storage, 0);
+        block_on(store.put(1, &42u16)).unwrap();
+        block_on(store.put(2, &1.5f32)).unwrap();
+        assert_eq!(block_on(store.get::<u16>(1)), Ok(42));
+        assert_eq!(block_on(store.get::<f32>(2)), Ok(1.5));
+        assert_eq!(block_on(store.get::<u16>(3)), Err(KvError::NotFound));
+        assert_eq!(block_on(store.get::<u32>(1)), Err(KvError::WrongSize));
+
+        let bytes = storage.bytes();
+        assert_eq!(get::<u16>(bytes, 1), Ok(42));
+        assert_eq!(get::<f32>(bytes, 2), Ok(1.5));
+    }
+
+    #[test]
+    fn invalid_puts() {
+        assert_eq!(put(erased(), FREE_KEY, 1u8).1, Err(KvError::InvalidKey));
+        assert_eq!(
+            put(erased(), 1, [0u8; MAX_KV_VALUE_LEN + 1]).1,
+            Err(KvError::ValueTooLarge)
+        );
+    }
+
+    #[test]
+    fn overwrite() {
+        let (bytes, _) = put(erased(), 1, 10u32);
+        let (bytes, _) = put(bytes, 2, 20u32);
+        let (bytes, result) = put(bytes, 1, 11u32);
+        assert_eq!(result, Ok(()));
+        assert_eq!(get::<u32>(bytes, 1), Ok(11));
+        assert_eq!(get::<u32>(bytes, 2), Ok(20));
+
+        // Writing the value that's already there doesn't cost anything
+        let storage = TestStorage::new(bytes, None);
+        block_on(KvStore::new(&storage, 0).put(1, &11u32)).unwrap();
+        assert_eq!(storage.writes.get(), 0);
+    }
+
+    #[test]
+    fn compaction() {
+        let storage = TestStorage::new(erased(), None);
+        let mut store = KvStore::new(&storage, 0);
+        block_on(store.put(1, &7u8)).unwrap();
+
+        // Enough overwrites to go all the way around the pages a couple of times
+        let record_len = RECORD_OVERHEAD + u32::SIZE as u16;
+        let iterations = 2 * PAGE_COUNT as u32 * (PAGE_SIZE / record_len) as u32;
+        for i in 0..iterations {
+            block_on(store.put(2, &i)).unwrap();
+        }
+        assert!(store.sequence as u32 >= 2 * PAGE_COUNT as u32 - 1);
+        assert_eq!(block_on(store.get::<u8>(1)), Ok(7));
+        assert_eq!(block_on(store.get::<u32>(2)), Ok(iterations - 1));
+
+        // The newest page wins at mount time
+        let bytes = storage.bytes();
+        assert_eq!(get::<u8>(bytes, 1), Ok(7));
+        assert_eq!(get::<u32>(bytes, 2), Ok(iterations - 1));
+    }
+
+    #[test]
+    fn full() {
+        let value = [0xa5u8; MAX_KV_VALUE_LEN];
+        let record_len = RECORD_OVERHEAD + MAX_KV_VALUE_LEN as u16;
+        let capacity = ((PAGE_SIZE - HEADER_SIZE) / record_len) as u8;
+
+        let storage = TestStorage::new(erased(), None);
+        let mut store = KvStore::new(&storage, 0);
+        for key in 0..capacity {
+            block_on(store.put(key, &value)).unwrap();
+        }
+        assert_eq!(block_on(store.put(capacity, &value)), Err(KvError::Full));
+
+        // Nothing was lost, and there's still room to replace the values we have
+        for key in 0..capacity {
+            assert_eq!(block_on(store.get::<[u8; MAX_KV_VALUE_LEN]>(key)), Ok(value));
+        }
+        block_on(store.put(0, &[0x5au8; MAX_KV_VALUE_LEN])).unwrap();
+        assert_eq!(
+            block_on(store.get::<[u8; MAX_KV_VALUE_LEN]>(0)),
+            Ok([0x5a; MAX_KV_VALUE_LEN])
+        );
+    }
+
+    // Fill the active page most of the way, so that the put under test has to compact; then cut the
+    // power after every possible number of writes and check that remounting finds either the old
+    // value or the new one, and that nothing else was disturbed
+    #[test]
+    fn power_cut_at_every_write() {
+        let mut bytes = erased();
+        let mut last = 0u32;
+        for i in 0..4u8 {
+            bytes = put(bytes, i, i as u32 * 100).0;
+        }
+        let record_len = RECORD_OVERHEAD + u32::SIZE as u16;
+        while {
+            let storage = TestStorage::new(bytes, None);
+            let mut store = KvStore::new(&storage, 0);
+            block_on(store.mount());
+            store.write_pos + record_len <= PAGE_SIZE
+        } {
+            last += 1;
+            bytes = put(bytes, 0, last).0;
+        }
+
+        let storage = TestStorage::new(bytes, None);
+        block_on(KvStore::new(&storage, 0).put(0, &0xdead_beefu32)).unwrap();
+        let total_writes = storage.writes.get();
+        assert!(total_writes > 0);
+
+        for cut_after in 0..=total_writes {
+            let storage = TestStorage::new(bytes, Some(cut_after));
+            block_on(KvStore::new(&storage, 0).put(0, &0xdead_beefu32)).unwrap();
+            let after = storage.bytes();
+
+            let value = get::<u32>(after, 0);
+            if cut_after == total_writes {
+                assert_eq!(value, Ok(0xdead_beef));
+            } else {
+                assert!(value == Ok(last) || value == Ok(0xdead_beef), "cut after {}", cut_after);
+            }
+            for i in 1..4u8 {
+                assert_eq!(get::<u32>(after, i), Ok(i as u32 * 100), "cut after {}", cut_after);
+            }
+
+            // The store still works after coming back up
+            let (after, result) = put(after, 4, 4u32);
+            assert_eq!(result, Ok(()), "cut after {}", cut_after);
+            assert_eq!(get::<u32>(after, 4), Ok(4));
+        }
+    }
+}
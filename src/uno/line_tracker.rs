@@ -0,0 +1,146 @@
+// Turning calibrated IR readings into the position of a line under the array.  This is kept apart
+// from the IRSensors driver so that it can be tested on the host.
+
+// Calibration maps every sensor onto 0 (the most reflective surface it saw) up to this (the least)
+pub const MAX_CALIBRATED_VALUE: u16 = 1000;
+const LINE_NOISE_THRESHOLD: u16 = 50; // calibrated readings below this don't count towards the position
+const LINE_DETECT_THRESHOLD: u16 = 200; // at least one sensor has to see this much to count as on the line
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineColor {
+    Dark,  // a dark line on a light surface, e.g. electrical tape on the table
+    Light, // a light line on a dark surface
+}
+
+#[derive(Clone, Copy)]
+pub struct LineReading {
+    // 0 when the line is under sensor 0, 1000 under sensor 1, and so on up to MAX_POSITION
+    pub position: u16,
+    pub detected: bool,
+}
+
+// Turns calibrated readings into a position along the array.  When the line is lost we report the
+// end of the array it was last seen nearest to, so a follower knows which way to turn to find it.
+pub struct LineTracker<const N: usize> {
+    last_position: u16,
+}
+
+impl<const N: usize> LineTracker<N> {
+    pub const MAX_POSITION: u16 = 1000 * (N as u16 - 1);
+
+    pub fn new() -> LineTracker<N> {
+        LineTracker {
+            last_position: Self::MAX_POSITION / 2,
+        }
+    }
+
+    pub fn update(&mut self, values: &[u16; N], color: LineColor) -> LineReading {
+        let mut weighted_sum: u32 = 0;
+        let mut sum: u32 = 0;
+        let mut detected = false;
+        for (i, &value) in values.iter().enumerate() {
+            let value = match color {
+                LineColor::Dark => value.min(MAX_CALIBRATED_VALUE),
+                LineColor::Light => MAX_CALIBRATED_VALUE - value.min(MAX_CALIBRATED_VALUE),
+            };
+            detected |= value > LINE_DETECT_THRESHOLD;
+            if value > LINE_NOISE_THRESHOLD {
+                weighted_sum += value as u32 * 1000 * i as u32;
+                sum += value as u32;
+            }
+        }
+
+        if !detected || sum == 0 {
+            let position = if self.last_position < Self::MAX_POSITION / 2 {
+                0
+            } else {
+                Self::MAX_POSITION
+            };
+            return LineReading {
+                position,
+                detected: false,
+            };
+        }
+
+        self.last_position = (weighted_sum / sum) as u16;
+        LineReading {
+            position: self.last_position,
+            detected: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position<const N: usize>(tracker: &mut LineTracker<N>, values: [u16; N], color: LineColor) -> Option<u16> {
+        let reading = tracker.update(&values, color);
+        if reading.detected {
+            Some(reading.position)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn position_is_the_weighted_average() {
+        let mut tracker = LineTracker::<3>::new();
+        assert_eq!(position(&mut tracker, [0, 1000, 0], LineColor::Dark), Some(1000));
+        assert_eq!(position(&mut tracker, [0, 500, 500], LineColor::Dark), Some(1500));
+        assert_eq!(position(&mut tracker, [1000, 250, 0], LineColor::Dark), Some(200));
+        assert_eq!(position(&mut tracker, [0, 0, 1000], LineColor::Dark), Some(2000));
+
+        // Readings in the noise don't pull the position around, and readings past the calibrated
+        // range count as full scale
+        assert_eq!(position(&mut tracker, [40, 1000, 0], LineColor::Dark), Some(1000));
+        assert_eq!(position(&mut tracker, [0, 1000, 1600], LineColor::Dark), Some(1500));
+
+        let mut tracker = LineTracker::<6>::new();
+        assert_eq!(
+            position(&mut tracker, [0, 0, 0, 800, 800, 0], LineColor::Dark),
+            Some(3500)
+        );
+    }
+
+    #[test]
+    fn light_lines_are_inverted() {
+        let mut tracker = LineTracker::<3>::new();
+        assert_eq!(position(&mut tracker, [0, 1000, 1000], LineColor::Dark), Some(1500));
+        assert_eq!(position(&mut tracker, [0, 1000, 1000], LineColor::Light), Some(0));
+        assert_eq!(position(&mut tracker, [1000, 1000, 0], LineColor::Light), Some(2000));
+
+        // The whole array over the light surface is a lost dark line, and a lost light line over
+        // the dark one
+        assert_eq!(position(&mut tracker, [0, 0, 0], LineColor::Dark), None);
+        assert_eq!(position(&mut tracker, [1000, 1000, 1000], LineColor::Light), None);
+    }
+
+    #[test]
+    fn faint_readings_are_not_a_line() {
+        let mut tracker = LineTracker::<3>::new();
+        assert_eq!(position(&mut tracker, [150, 180, 150], LineColor::Dark), None);
+    }
+
+    #[test]
+    fn lost_line_reports_the_side_it_was_last_seen_on() {
+        let mut tracker = LineTracker::<6>::new();
+        let max = LineTracker::<6>::MAX_POSITION;
+
+        assert_eq!(
+            position(&mut tracker, [0, 300, 1000, 0, 0, 0], LineColor::Dark),
+            Some(1769)
+        );
+        let lost = tracker.update(&[0; 6], LineColor::Dark);
+        assert!(!lost.detected);
+        assert_eq!(lost.position, 0);
+        // ...and it stays there however long the line is gone
+        assert_eq!(tracker.update(&[0; 6], LineColor::Dark).position, 0);
+
+        assert_eq!(
+            position(&mut tracker, [0, 0, 0, 0, 1000, 200], LineColor::Dark),
+            Some(4166)
+        );
+        assert_eq!(tracker.update(&[0; 6], LineColor::Dark).position, max);
+    }
+}
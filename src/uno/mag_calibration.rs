@@ -0,0 +1,372 @@
+// Hard- and soft-iron calibration for the magnetometer.  While the robot spins in place, the x/y
+// magnetometer readings trace out an ellipse (instead of a circle centered on the origin) because
+// of nearby iron and the motors.  We fit a general conic
+//
+//     A u^2 + B uv + C v^2 + D u + E v = 1
+//
+// to the samples by least squares, accumulating the normal equations as we go so we don't have to
+// store the samples.  The center of the ellipse is the hard-iron offset, and the square root of
+// the (normalized) quadratic form is the soft-iron matrix that maps the ellipse onto the unit
+// circle.
+//
+// Spinning on a level floor can't separate the z-axis offset from the vertical component of the
+// earth's field, since both are constant throughout the turn.  So we just record the mean z reading
+// while level, and rebuild z from that and the local magnetic inclination (the angle the field dips
+// below horizontal, which depends on where on earth the robot is).
+//
+// Nothing in here touches the hardware, so it can be exercised on the host.
+use crate::{
+    eeprom_record,
+    math::Vector3,
+};
+use micromath::F32Ext;
+
+const UNKNOWNS: usize = 5;
+
+#[derive(Clone, Copy)]
+pub struct MagCalibration {
+    pub offset: (f32, f32), // raw counts
+    pub matrix: [f32; 4],   // 2x2 row-major, in 1 / raw counts
+    pub z_level: f32,       // raw counts, the mean z reading while spinning level
+}
+
+eeprom_record!(MagCalibration {
+    offset: (f32, f32),
+    matrix: [f32; 4],
+    z_level: f32,
+});
+
+impl MagCalibration {
+    pub fn is_valid(&self) -> bool {
+        self.offset.0.is_finite()
+            && self.offset.1.is_finite()
+            && self.matrix.iter().all(|m| m.is_finite())
+            && self.z_level.is_finite()
+            && self.det() > 0.0
+    }
+
+    // Map a raw reading so that the horizontal component of the earth's field has length 1.  z is
+    // scaled by the average x/y gain, and is centered so that it reads `vertical_field` (the
+    // earth's field along the sensor's z-axis, in the same units) when the robot is level.
+    pub fn apply(&self, raw: Vector3, vertical_field: f32) -> Vector3 {
+        let (dx, dy) = (raw.0 - self.offset.0, raw.1 - self.offset.1);
+        let m = &self.matrix;
+        let z = (raw.2 - self.z_level) * self.det().sqrt() + vertical_field;
+        (m[0] * dx + m[1] * dy, m[2] * dx + m[3] * dy, z)
+    }
+
+    fn det(&self) -> f32 {
+        self.matrix[0] * self.matrix[3] - self.matrix[1] * self.matrix[2]
+    }
+}
+
+pub struct CalibrationQuality {
+    // RMS of the algebraic fit error; well-behaved data is below ~0.05
+    pub residual: f32,
+    // How many of the eight 45-degree sectors around the center had at least one sample; anything
+    // less than 8 means the robot didn't make a full turn
+    pub sectors_covered: u8,
+}
+
+pub struct EllipseFit {
+    center: (f32, f32),
+    scale: f32,
+    ata: [f32; UNKNOWNS * (UNKNOWNS + 1) / 2], // upper triangle of the normal matrix
+    atb: [f32; UNKNOWNS],
+    z_sum: f32,
+    count: u16,
+    sectors: u8,
+}
+
+impl EllipseFit {
+    // `center` and `scale` should be rough estimates of the ellipse's center and radius (e.g.,
+    // from min/max); the samples are normalized with them to keep the f32 math well-conditioned
+    pub fn new(center: (f32, f32), scale: f32) -> EllipseFit {
+        EllipseFit {
+            center,
+            scale,
+            ata: [0.0; UNKNOWNS * (UNKNOWNS + 1) / 2],
+            atb: [0.0; UNKNOWNS],
+            z_sum: 0.0,
+            count: 0,
+            sectors: 0,
+        }
+    }
+
+    pub fn add(&mut self, x: f32, y: f32, z: f32) {
+        let (u, v) = ((x - self.center.0) / self.scale, (y - self.center.1) / self.scale);
+        let row = [u * u, u * v, v * v, u, v];
+        let mut k = 0;
+        for i in 0..UNKNOWNS {
+            self.atb[i] += row[i];
+            for j in i..UNKNOWNS {
+                self.ata[k] += row[i] * row[j];
+                k += 1;
+            }
+        }
+        self.z_sum += z;
+        self.count += 1;
+        self.sectors |= 1 << sector(u, v);
+    }
+
+    // Returns None if the samples don't describe an ellipse
+    pub fn solve(&self) -> Option<(MagCalibration, CalibrationQuality)> {
+        let normal = self.normal_matrix();
+        let mut augmented = [[0.0; UNKNOWNS + 1]; UNKNOWNS];
+        for i in 0..UNKNOWNS {
+            augmented[i][..UNKNOWNS].copy_from_slice(&normal[i]);
+            augmented[i][UNKNOWNS] = self.atb[i];
+        }
+        let p = gaussian_solve(augmented)?;
+        let [a, b, c, d, e] = p;
+
+        let det = 4.0 * a * c - b * b;
+        if det <= 0.0 {
+            return None;
+        }
+        let (uc, vc) = ((b * e - 2.0 * c * d) / det, (b * d - 2.0 * a * e) / det);
+        let k = 1.0 - (a * uc * uc + b * uc * vc + c * vc * vc + d * uc + e * vc);
+        if k <= 0.0 {
+            return None;
+        }
+
+        // The symmetric square root of a 2x2 positive-definite matrix M is (M + sI) / t, where
+        // s = sqrt(det M) and t = sqrt(trace M + 2s)
+        let (qa, qb, qc) = (a / k, b / (2.0 * k), c / k);
+        let s = (qa * qc - qb * qb).sqrt();
+        let t = (qa + qc + 2.0 * s).sqrt();
+        let w = [(qa + s) / t, qb / t, qb / t, (qc + s) / t];
+
+        let calibration = MagCalibration {
+            offset: (self.center.0 + self.scale * uc, self.center.1 + self.scale * vc),
+            matrix: [
+                w[0] / self.scale,
+                w[1] / self.scale,
+                w[2] / self.scale,
+                w[3] / self.scale,
+            ],
+            z_level: self.z_sum / self.count as f32,
+        };
+
+        // Sum of squared residuals is p'(A'A)p - 2p'(A'b) + n
+        let mut ssr = self.count as f32;
+        for i in 0..UNKNOWNS {
+            ssr -= 2.0 * p[i] * self.atb[i];
+            for j in 0..UNKNOWNS {
+                ssr += p[i] * normal[i][j] * p[j];
+            }
+        }
+        let ssr = if ssr > 0.0 { ssr } else { 0.0 }; // rounding error can push it slightly negative
+        let quality = CalibrationQuality {
+            residual: (ssr / self.count as f32).sqrt(),
+            sectors_covered: self.sectors.count_ones() as u8,
+        };
+
+        if calibration.is_valid() {
+            Some((calibration, quality))
+        } else {
+            None
+        }
+    }
+
+    fn normal_matrix(&self) -> [[f32; UNKNOWNS]; UNKNOWNS] {
+        let mut normal = [[0.0; UNKNOWNS]; UNKNOWNS];
+        let mut k = 0;
+        for i in 0..UNKNOWNS {
+            for j in i..UNKNOWNS {
+                normal[i][j] = self.ata[k];
+                normal[j][i] = self.ata[k];
+                k += 1;
+            }
+        }
+        normal
+    }
+}
+
+// Which 45-degree sector around the origin (u, v) is in
+fn sector(u: f32, v: f32) -> u8 {
+    ((u < 0.0) as u8) << 2 | ((v < 0.0) as u8) << 1 | (u.abs() < v.abs()) as u8
+}
+
+// Gaussian elimination with partial pivoting on an augmented matrix
+fn gaussian_solve(mut m: [[f32; UNKNOWNS + 1]; UNKNOWNS]) -> Option<[f32; UNKNOWNS]> {
+    for col in 0..UNKNOWNS {
+        let mut pivot = col;
+        for row in col + 1..UNKNOWNS {
+            if m[row][col].abs() > m[pivot][col].abs() {
+                pivot = row;
+            }
+        }
+        if m[pivot][col].abs() < 1e-9 {
+            return None;
+        }
+        m.swap(col, pivot);
+
+        for row in col + 1..UNKNOWNS {
+            let factor = m[row][col] / m[col][col];
+            for k in col..=UNKNOWNS {
+                m[row][k] -= factor * m[col][k];
+            }
+        }
+    }
+
+    let mut x = [0.0; UNKNOWNS];
+    for row in (0..UNKNOWNS).rev() {
+        let mut sum = m[row][UNKNOWNS];
+        for k in row + 1..UNKNOWNS {
+            sum -= m[row][k] * x[k];
+        }
+        x[row] = sum / m[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::PI;
+
+    const OFFSET: (f32, f32) = (310.0, -145.0);
+    const SOFT_IRON: [f32; 4] = [520.0, 90.0, 90.0, 380.0]; // symmetric, maps the unit circle onto the ellipse
+    const Z_LEVEL: f32 = -730.0;
+
+    fn ellipse_point(theta: f32) -> (f32, f32) {
+        let (c, s) = (theta.cos(), theta.sin());
+        (
+            OFFSET.0 + SOFT_IRON[0] * c + SOFT_IRON[1] * s,
+            OFFSET.1 + SOFT_IRON[2] * c + SOFT_IRON[3] * s,
+        )
+    }
+
+    // Feeds `count` samples spread evenly over `arc` radians, with the rough center and scale taken
+    // from min/max like the calibration state does
+    fn fit_arc(arc: f32, count: usize, noise: f32) -> EllipseFit {
+        let points: Vec<(f32, f32)> = (0..count)
+            .map(|i| {
+                let (x, y) = ellipse_point(arc * i as f32 / count as f32);
+                // deterministic "noise" that isn't correlated with the angle
+                let jitter = noise * ((i * 7919 % 13) as f32 / 6.0 - 1.0);
+                (x + jitter, y - jitter)
+            })
+            .collect();
+        let (mut min, mut max) = ((f32::MAX, f32::MAX), (f32::MIN, f32::MIN));
+        for &(x, y) in &points {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+        let center = ((min.0 + max.0) / 2.0, (min.1 + max.1) / 2.0);
+        let scale = ((max.0 - min.0) / 2.0).max((max.1 - min.1) / 2.0);
+
+        let mut fit = EllipseFit::new(center, scale);
+        for (x, y) in points {
+            fit.add(x, y, Z_LEVEL);
+        }
+        fit
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn recovers_offset_and_matrix() {
+        let (calibration, quality) = fit_arc(2.0 * PI, 200, 0.0).solve().unwrap();
+        assert_close(calibration.offset.0, OFFSET.0, 0.5);
+        assert_close(calibration.offset.1, OFFSET.1, 0.5);
+
+        // The fit should be the inverse of the soft-iron distortion
+        let det = SOFT_IRON[0] * SOFT_IRON[3] - SOFT_IRON[1] * SOFT_IRON[2];
+        let inverse = [
+            SOFT_IRON[3] / det,
+            -SOFT_IRON[1] / det,
+            -SOFT_IRON[2] / det,
+            SOFT_IRON[0] / det,
+        ];
+        for i in 0..4 {
+            assert_close(calibration.matrix[i], inverse[i], 1e-5);
+        }
+
+        for i in 0..16 {
+            let (x, y) = ellipse_point(i as f32 * PI / 8.0);
+            let (u, v, _) = calibration.apply((x, y, Z_LEVEL), 0.0);
+            assert_close((u * u + v * v).sqrt(), 1.0, 1e-3);
+        }
+
+        assert!(quality.residual < 1e-3, "residual {}", quality.residual);
+        assert_eq!(quality.sectors_covered, 8);
+    }
+
+    #[test]
+    fn z_is_centered_on_the_vertical_field() {
+        let (calibration, _) = fit_arc(2.0 * PI, 200, 0.0).solve().unwrap();
+        assert_close(calibration.z_level, Z_LEVEL, 1e-2);
+
+        // Level, z reads the vertical field whatever it is; tilted, it moves by the x/y gain
+        let (x, y) = ellipse_point(0.0);
+        assert_close(calibration.apply((x, y, Z_LEVEL), -1.5).2, -1.5, 1e-4);
+        let tilted = calibration.apply((x, y, Z_LEVEL + 200.0), -1.5);
+        assert_close(tilted.2, 200.0 * calibration.det().sqrt() - 1.5, 1e-4);
+    }
+
+    #[test]
+    fn quality_reflects_noise_and_coverage() {
+        let (_, noisy) = fit_arc(2.0 * PI, 200, 20.0).solve().unwrap();
+        assert!(noisy.residual > 0.01, "residual {}", noisy.residual);
+        assert!(noisy.residual < 0.1, "residual {}", noisy.residual);
+
+        let (_, half_turn) = fit_arc(PI, 200, 0.0).solve().unwrap();
+        assert!(half_turn.sectors_covered < 8, "sectors {}", half_turn.sectors_covered);
+    }
+
+    #[test]
+    fn degenerate_samples() {
+        // The robot never turned
+        let mut fit = EllipseFit::new(OFFSET, 400.0);
+        for _ in 0..100 {
+            fit.add(500.0, 20.0, Z_LEVEL);
+        }
+        assert!(fit.solve().is_none());
+
+        // The robot was pushed in a straight line
+        let mut fit = EllipseFit::new(OFFSET, 400.0);
+        for i in 0..100 {
+            fit.add(OFFSET.0 + 4.0 * i as f32, OFFSET.1 - 2.0 * i as f32, Z_LEVEL);
+        }
+        assert!(fit.solve().is_none());
+
+        assert!(EllipseFit::new(OFFSET, 400.0).solve().is_none());
+    }
+
+    #[test]
+    fn gaussian_solve_known_system() {
+        // The pivots are deliberately out of order to exercise the row swaps
+        let m = [
+            [0.0, 2.0, 0.0, 0.0, 1.0, 7.0],
+            [1.0, 0.0, 0.0, 0.0, 0.0, 1.0],
+            [0.0, 0.0, 0.0, 3.0, 0.0, 12.0],
+            [0.0, 1.0, 4.0, 0.0, 0.0, 14.0],
+            [2.0, 0.0, 0.0, 1.0, 5.0, 31.0],
+        ];
+        let x = gaussian_solve(m).unwrap();
+        for (actual, expected) in x.iter().zip(&[1.0, 1.0, 3.25, 4.0, 5.0]) {
+            assert_close(*actual, *expected, 1e-4);
+        }
+    }
+
+    #[test]
+    fn gaussian_solve_singular() {
+        let mut m = [[0.0; UNKNOWNS + 1]; UNKNOWNS];
+        for i in 0..UNKNOWNS {
+            m[i][i] = 1.0;
+            m[i][UNKNOWNS] = 1.0;
+        }
+        m[4] = m[3]; // two identical rows
+        assert!(gaussian_solve(m).is_none());
+    }
+}
@@ -1,9 +1,29 @@
+pub mod adc;
+pub mod bump;
+pub mod bump_detector;
+mod console;
+pub mod edge;
 pub mod eeprom;
-mod imu;
-mod ir_sensors;
+pub mod eeprom_value;
+mod gyro;
+mod heading;
+pub mod imu;
+mod imu_chips;
+#[cfg(feature = "analog-ir")]
+mod ir_analog;
+#[cfg(not(feature = "analog-ir"))]
+mod ir_rc;
+pub mod ir_sensors;
+pub mod kv_store;
+pub mod line_tracker;
+pub mod mag_calibration;
 pub mod motor;
+pub mod orientation;
 mod pushbutton;
+pub mod tap;
+pub mod tap_detector;
 pub mod timers;
+pub mod twi;
 
 use crate::{
     avr_async::{
@@ -11,11 +31,33 @@ use crate::{
         Waiter,
     },
     mem::Allocator,
+    params::{
+        self,
+        Param,
+    },
     uno::{
+        bump::BumpMonitor,
+        console::Console,
         eeprom::*,
+        gyro::Gyro,
+        heading::HeadingEstimator,
         imu::IMU,
-        ir_sensors::IRSensors,
+        ir_sensors::{
+            IRBackend,
+            IRCalibrationVector,
+            IRPin,
+            IRSensors,
+            Port,
+        },
+        kv_store::{
+            KvError,
+            KvKey,
+            KvStore,
+        },
+        orientation::OrientationMonitor,
         pushbutton::Pushbutton,
+        tap::TapMonitor,
+        twi::Twi,
     },
 };
 use arduino_uno::{
@@ -32,33 +74,59 @@ use arduino_uno::{
             Usart0,
         },
     },
-    pac::{
-        EEPROM,
-        TC0 as Timer0,
-    },
+    pac::TC0 as Timer0,
     prelude::*,
 };
 use avr_hal_generic::avr_device;
-use core::{
-    cell::RefCell,
-    future::Future,
-};
+use core::future::Future;
 use micromath::F32Ext;
 use void::ResultVoidExt;
 
 pub use motor::MotorController;
 
 const SERIAL_BAUD: u32 = 57600;
-const I2C_SPEED: u32 = 25000;
+const I2C_SPEED: u32 = 400000; // every IMU and gyro chip we support can do fast mode
+
+// The IR profiles in the EEPROM have room for up to MAX_IR_SENSORS
+#[cfg(not(feature = "analog-ir"))]
+pub const IR_SENSOR_COUNT: usize = 6;
+#[cfg(not(feature = "analog-ir"))]
+const IR_PINS: [IRPin; IR_SENSOR_COUNT] = [
+    IRPin::new(Port::D, 5),
+    IRPin::new(Port::C, 2),
+    IRPin::new(Port::C, 0),
+    IRPin::new(Port::B, 3),
+    IRPin::new(Port::C, 3),
+    IRPin::new(Port::D, 4),
+];
+
+// A4 and A5 are taken by I2C, which leaves four analog inputs
+#[cfg(feature = "analog-ir")]
+pub const IR_SENSOR_COUNT: usize = 4;
+#[cfg(feature = "analog-ir")]
+const IR_CHANNELS: [u8; IR_SENSOR_COUNT] = [0, 1, 2, 3];
+
+// Without the LEDON jumper the emitters are always on, so an "ambient" read would just be a second
+// lit read; ambient compensation is skipped unless the build says the jumper is there
+const IR_EMITTER: Option<IRPin> = if cfg!(feature = "ir-emitter") {
+    Some(IRPin::new(Port::D, 2))
+} else {
+    None
+};
 
 pub struct Uno {
-    pub serial: Usart0<MHz16, Floating>,
+    pub console: &'static Console,
     timer0: Timer0,
 
-    pub ddr: arduino_uno::DDR,
-    eeprom: EEPROM,
-    pub imu: IMU,
-    pub ir_sensors: IRSensors,
+    pub eeprom: &'static Eeprom,
+    kv_store: KvStore<'static, Eeprom>,
+    pub imu: &'static IMU,
+    pub gyro: &'static Gyro,
+    pub heading: &'static HeadingEstimator,
+    pub bump: &'static BumpMonitor,
+    pub orientation: &'static OrientationMonitor,
+    pub taps: &'static TapMonitor,
+    pub ir_sensors: &'static IRSensors<IR_SENSOR_COUNT>,
     pub motor_controller: &'static MotorController,
     pub pushbutton: Pushbutton,
     pub led: PB5<Output>,
@@ -74,12 +142,6 @@ impl Uno {
             pins.d1.into_output(&pins.ddr),
             SERIAL_BAUD.into_baudrate(),
         );
-        let i2c = arduino_uno::I2cMaster::new(
-            board.TWI,
-            pins.a4.into_pull_up_input(&pins.ddr),
-            pins.a5.into_pull_up_input(&pins.ddr),
-            I2C_SPEED,
-        );
 
         let led = pins.d13.into_output(&pins.ddr);
         let pushbutton = Pushbutton::new(pins.d12.into_pull_up_input(&pins.ddr));
@@ -95,16 +157,53 @@ impl Uno {
             pins.d7.into_output(&pins.ddr),
             pins.d9.into_output(&pins.ddr).into_pwm(&mut pwm_timer),
         );
+        let eeprom = Eeprom::new(board.EEPROM);
+        let console = Console::new(serial);
+
+        // The I2C devices are configured right away, and need the timers running for their timeouts
         timers::init_timers(&board.TC0);
+        let twi = Twi::new(
+            board.TWI,
+            pins.a4.into_pull_up_input(&pins.ddr),
+            pins.a5.into_pull_up_input(&pins.ddr),
+            I2C_SPEED,
+        );
+        let gyro = Gyro::new(twi);
+        let imu = IMU::new(twi);
+        console.write_line(imu.chip_name().unwrap_or("imu not detected"));
+        let heading = HeadingEstimator::new(imu, gyro, motor_controller);
+        let bump = BumpMonitor::new(imu);
+        let orientation = OrientationMonitor::new(imu, motor_controller);
+        let taps = TapMonitor::new(imu, motor_controller);
+        #[cfg(not(feature = "analog-ir"))]
+        let ir_backend = IRBackend::new(IR_PINS);
+        #[cfg(feature = "analog-ir")]
+        let ir_backend = IRBackend::new(adc::Adc::new(), IR_CHANNELS);
+        let ir_sensors = IRSensors::new(ir_backend, IR_EMITTER);
         executor.add_async_driver(motor_controller.get_motor_driver());
+        executor.add_async_driver(eeprom.get_eeprom_driver());
+        executor.add_async_driver(console.get_console_driver());
+        executor.add_async_driver(gyro.get_gyro_driver());
+        executor.add_async_driver(imu.get_mag_sampling_driver());
+        executor.add_async_driver(imu.get_accel_sampling_driver());
+        executor.add_async_driver(heading.get_heading_driver());
+        executor.add_async_driver(bump.get_bump_driver());
+        executor.add_async_driver(orientation.get_orientation_driver());
+        executor.add_async_driver(taps.get_tap_driver());
+        executor.add_async_driver(ir_sensors.get_ir_driver());
         Allocator::get().new(Uno {
-            serial,
+            console,
             timer0: board.TC0,
 
-            ddr: pins.ddr,
-            eeprom: board.EEPROM,
-            imu: IMU::new(i2c),
-            ir_sensors: IRSensors::new(pins.d5, pins.a2, pins.a0, pins.d11, pins.a3, pins.d4),
+            eeprom,
+            kv_store: KvStore::new(eeprom, KV_REGION.addr),
+            imu,
+            gyro,
+            heading,
+            bump,
+            orientation,
+            taps,
+            ir_sensors,
             motor_controller,
             pushbutton,
             led,
@@ -120,29 +219,42 @@ impl Uno {
         }
     }
 
-    pub async fn load_calibration_data(&mut self) {
-        let imu_calibration_vector = (
-            self.read_eeprom_u16(IMU_X_MIN_ADDR).await as i16,
-            self.read_eeprom_u16(IMU_X_MAX_ADDR).await as i16,
-            self.read_eeprom_u16(IMU_Y_MIN_ADDR).await as i16,
-            self.read_eeprom_u16(IMU_Y_MAX_ADDR).await as i16,
-        );
-        self.imu.set_calibration_vector(imu_calibration_vector);
-
-        let ir_calibration_vector = (
-            self.read_eeprom_u16(IR_0_MIN_ADDR).await,
-            self.read_eeprom_u16(IR_0_MAX_ADDR).await,
-            self.read_eeprom_u16(IR_1_MIN_ADDR).await,
-            self.read_eeprom_u16(IR_1_MAX_ADDR).await,
-            self.read_eeprom_u16(IR_2_MIN_ADDR).await,
-            self.read_eeprom_u16(IR_2_MAX_ADDR).await,
-            self.read_eeprom_u16(IR_3_MIN_ADDR).await,
-            self.read_eeprom_u16(IR_3_MAX_ADDR).await,
-            self.read_eeprom_u16(IR_4_MIN_ADDR).await,
-            self.read_eeprom_u16(IR_4_MAX_ADDR).await,
-            self.read_eeprom_u16(IR_5_MIN_ADDR).await,
-            self.read_eeprom_u16(IR_5_MAX_ADDR).await,
-        );
+    // Returns false if the active profile hasn't been calibrated yet
+    pub async fn load_calibration_data(&mut self) -> bool {
+        let mag_calibration = self.eeprom.read(MAG_CALIBRATION).await.expect("read failed");
+        if !mag_calibration.is_valid() {
+            return false;
+        }
+        self.imu.set_mag_calibration(mag_calibration);
+
+        // An erased EEPROM reads back as NaN, which is also what's left there when the gyro was
+        // missing or failing during calibration.  That isn't worth recalibrating over (it would
+        // just fail again), so the gyro runs uncorrected and the magnetometer fusion in the
+        // heading estimator pulls back whatever drift that causes.
+        let gyro_bias: (f32, f32, f32) = self.eeprom.read(GYRO_BIAS).await.expect("read failed");
+        if gyro_bias.0.is_finite() && gyro_bias.1.is_finite() && gyro_bias.2.is_finite() {
+            self.gyro.set_bias(gyro_bias);
+        } else {
+            self.gyro.set_bias((0.0, 0.0, 0.0));
+        }
+
+        let ir_calibration_vector = self.eeprom.read(active_ir_profile()).await.expect("read failed");
+        if ir_calibration_vector.iter().any(|&(min, max)| min >= max) {
+            return false;
+        }
         self.ir_sensors.set_calibration_vector(ir_calibration_vector);
+        true
+    }
+
+    pub async fn kv_get<T: EepromValue>(&mut self, key: KvKey) -> Result<T, KvError> {
+        self.kv_store.get(key).await
     }
+
+    pub async fn kv_put<T: EepromValue>(&mut self, key: KvKey, value: &T) -> Result<(), KvError> {
+        self.kv_store.put(key, value).await
+    }
+}
+
+pub fn active_ir_profile() -> EepromAddr<IRCalibrationVector<IR_SENSOR_COUNT>> {
+    ir_profile(params::get_u32(Param::Profile) as usize).expect("invalid profile")
 }
@@ -1,6 +1,10 @@
 use crate::{
     avr_async::Waiter,
     mem::Allocator,
+    params::{
+        self,
+        Param,
+    },
 };
 use arduino_uno::hal::{
     port::{
@@ -11,7 +15,10 @@ use arduino_uno::hal::{
     pwm,
 };
 use core::{
-    cell::RefCell,
+    cell::{
+        Cell,
+        RefCell,
+    },
     future::Future,
 };
 use embedded_hal::{
@@ -28,7 +35,6 @@ type LeftThrottlePin = PB2<Pwm<pwm::Timer1Pwm>>;
 type RightDirectionPin = PD7<Output>;
 type RightThrottlePin = PB1<Pwm<pwm::Timer1Pwm>>;
 
-const MAX_MOTOR_DELTA: f32 = 0.1; // 10% of full power
 const UPDATE_DELAY_MS: u32 = 10;
 const BORROW_MUT_DELAY_MS: u32 = 5;
 
@@ -53,9 +59,10 @@ where
     PT: 'static + PwmPin<Duty = u8>,
 {
     fn update(&mut self, target_value: f32) {
+        let max_delta = params::get(Param::MaxMotorDelta);
         self.current_value = match self.current_value {
-            cv if cv < target_value - MAX_MOTOR_DELTA => cv + MAX_MOTOR_DELTA,
-            cv if cv > target_value + MAX_MOTOR_DELTA => cv - MAX_MOTOR_DELTA,
+            cv if cv < target_value - max_delta => cv + max_delta,
+            cv if cv > target_value + max_delta => cv - max_delta,
             _ => target_value,
         };
 
@@ -73,6 +80,7 @@ pub struct MotorController {
     left_target: RefCell<f32>,
     right: RefCell<SingleMotorController<RightDirectionPin, RightThrottlePin>>,
     right_target: RefCell<f32>,
+    locked: Cell<bool>,
 }
 
 impl MotorController {
@@ -100,10 +108,14 @@ impl MotorController {
                 current_value: 0.0,
             }),
             right_target: RefCell::new(0.0),
+            locked: Cell::new(false),
         })
     }
 
     pub fn set_targets(&self, left_target: f32, right_target: f32) {
+        if self.locked.get() {
+            return;
+        }
         if let Ok(mut lt) = self.left_target.try_borrow_mut() {
             *lt = left_target;
         }
@@ -121,6 +133,32 @@ impl MotorController {
         }
     }
 
+    // Cut power to both motors right now, skipping the ramp down, and ignore any new targets until
+    // `unlock` is called
+    pub fn emergency_stop(&self) {
+        self.set_targets(0.0, 0.0);
+        self.locked.set(true);
+        if let Ok(mut left) = self.left.try_borrow_mut() {
+            left.current_value = 0.0;
+            left.update(0.0);
+        }
+        if let Ok(mut right) = self.right.try_borrow_mut() {
+            right.current_value = 0.0;
+            right.update(0.0);
+        }
+    }
+
+    pub fn unlock(&self) {
+        self.locked.set(false);
+    }
+
+    // True if either motor is still turning (including while ramping down)
+    pub fn is_driving(&self) -> bool {
+        let left = self.left.try_borrow().map_or(true, |l| l.current_value != 0.0);
+        let right = self.right.try_borrow().map_or(true, |r| r.current_value != 0.0);
+        left || right
+    }
+
     pub fn get_motor_driver(&'static self) -> &'static mut dyn Future<Output = !> {
         let future = async move || loop {
             if let Ok(mut left) = self.left.try_borrow_mut() {
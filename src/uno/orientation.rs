@@ -0,0 +1,132 @@
+// Safety cutoff for when the robot goes over the edge of the table: if the accelerometer says
+// we're upside down, steeply tilted, or falling, the motors are stopped on the spot (without
+// ramping down) and locked off, and the state machine is kicked into the Halted state.  Everything
+// stays off until someone rights the robot and presses the button.
+use crate::{
+    math::{
+        self,
+        Vector3,
+    },
+    mem::Allocator,
+    params::{
+        self,
+        Param,
+    },
+    uno::{
+        imu::IMU,
+        MotorController,
+    },
+};
+use core::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    future::Future,
+    task::Waker,
+};
+use micromath::F32Ext;
+
+const PI: f32 = 3.1415926;
+const TRIP_SAMPLES: u8 = 3; // 60ms of samples, so a bump or a bounce doesn't trip it
+const FREEFALL_G: f32 = 0.3;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Orientation {
+    Upright,
+    Tilted, // also covers falling, when there's no gravity to speak of
+    Flipped,
+}
+
+// The accelerometer's z-axis points up out of the robot, so it reads +1g when we're sitting flat
+pub fn classify(accel: Vector3, max_tilt_degrees: f32) -> Orientation {
+    if math::norm(accel) < FREEFALL_G {
+        return Orientation::Tilted;
+    }
+    let up = match math::normalize(accel) {
+        Some(up) => up,
+        None => return Orientation::Tilted,
+    };
+
+    if up.2 < 0.0 {
+        Orientation::Flipped
+    } else if up.2 < (max_tilt_degrees * PI / 180.0).cos() {
+        Orientation::Tilted
+    } else {
+        Orientation::Upright
+    }
+}
+
+pub struct OrientationMonitor {
+    imu: &'static IMU,
+    motor_controller: &'static MotorController,
+    orientation: Cell<Orientation>,
+    bad_samples: Cell<u8>,
+    tripped: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+
+impl OrientationMonitor {
+    // Like the MotorController, this is a static reference because the driver holds onto "self"
+    pub fn new(imu: &'static IMU, motor_controller: &'static MotorController) -> &'static OrientationMonitor {
+        Allocator::get().new(OrientationMonitor {
+            imu,
+            motor_controller,
+            orientation: Cell::new(Orientation::Upright),
+            bad_samples: Cell::new(0),
+            tripped: Cell::new(false),
+            waker: RefCell::new(None),
+        })
+    }
+
+    pub fn is_upright(&self) -> bool {
+        self.orientation.get() == Orientation::Upright
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.tripped.get()
+    }
+
+    // Whoever registers here gets woken up when the cutoff trips
+    pub fn register_waker(&self, waker: &Waker) {
+        *self.waker.borrow_mut() = Some(waker.clone());
+    }
+
+    // Re-arm the cutoff and let the motors run again
+    pub fn reset(&self) {
+        self.bad_samples.set(0);
+        self.tripped.set(false);
+        self.motor_controller.unlock();
+    }
+
+    pub fn get_orientation_driver(&'static self) -> &'static mut dyn Future<Output = !> {
+        let future = async move || {
+            let mut sequence = 0;
+            loop {
+                // Every accelerometer sample gets looked at; if the IMU is down this waits (keeping
+                // whatever we saw last) until the heading estimator brings it back
+                let sample = self.imu.next_accelerometer(sequence).await;
+                sequence = sample.sequence;
+
+                let orientation = classify(sample.value, params::get(Param::MaxTiltDegrees));
+                self.orientation.set(orientation);
+                if orientation == Orientation::Upright {
+                    self.bad_samples.set(0);
+                } else {
+                    self.bad_samples.set(self.bad_samples.get().saturating_add(1));
+                }
+
+                // A bump never turns us upside down, so there's no point waiting to be sure
+                let trip = orientation == Orientation::Flipped || self.bad_samples.get() >= TRIP_SAMPLES;
+                if trip && !self.tripped.get() {
+                    self.motor_controller.emergency_stop();
+                    self.tripped.set(true);
+                    if let Some(waker) = self.waker.borrow_mut().take() {
+                        waker.wake();
+                    }
+                }
+            }
+        };
+        Allocator::get().new(future())
+    }
+}
@@ -1,7 +1,10 @@
 use crate::{
     avr_async::Waiter,
     mem::Allocator,
-    state_machine::UPDATE_DELAY_MS,
+    params::{
+        self,
+        Param,
+    },
     uno::timers,
 };
 use arduino_uno::{
@@ -59,7 +62,7 @@ impl Pushbutton {
         let mut triggered = false;
         while timers::millis() <= end_time_ms.unwrap_or(u32::MAX) {
             if !check_fn(&self.pin).void_unwrap() {
-                Waiter::new(UPDATE_DELAY_MS).await;
+                Waiter::new(params::get_u32(Param::UpdateDelayMs)).await;
                 continue;
             }
             Waiter::new(DEBOUNCE_MS).await;
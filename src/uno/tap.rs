@@ -0,0 +1,122 @@
+// Tap gestures from the accelerometer, as a second way to poke the robot besides the pushbutton.
+// A tap is a short spike in acceleration (on any axis) that dies away within a couple of samples;
+// anything longer is someone picking the robot up or pushing it around.  A second tap shortly
+// after the first makes it a double tap.
+//
+// The TapDetector lives in tap_detector.rs so it can be fed recorded traces on the host; the
+// TapMonitor driver feeds it live samples, but only while the motors are stopped, since they shake
+// the robot far more than a tap does.
+pub use crate::uno::tap_detector::{
+    Tap,
+    TapConfig,
+    TapDetector,
+};
+use crate::{
+    avr_async::Waiter,
+    mem::Allocator,
+    params::{
+        self,
+        Param,
+    },
+    uno::{
+        imu::IMU,
+        MotorController,
+    },
+};
+use core::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    future::Future,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+};
+
+const IDLE_DELAY_MS: u32 = 100;
+
+impl TapConfig {
+    pub fn from_params() -> TapConfig {
+        TapConfig {
+            threshold_g: params::get(Param::TapThreshold),
+            double_tap_window_ms: params::get_u32(Param::DoubleTapWindowMs),
+        }
+    }
+}
+
+pub struct TapMonitor {
+    imu: &'static IMU,
+    motor_controller: &'static MotorController,
+    detector: RefCell<TapDetector>,
+    tap: Cell<Option<Tap>>,
+    waker: RefCell<Option<Waker>>,
+}
+
+impl TapMonitor {
+    // Like the MotorController, this is a static reference because the driver holds onto "self"
+    pub fn new(imu: &'static IMU, motor_controller: &'static MotorController) -> &'static TapMonitor {
+        Allocator::get().new(TapMonitor {
+            imu,
+            motor_controller,
+            detector: RefCell::new(TapDetector::new()),
+            tap: Cell::new(None),
+            waker: RefCell::new(None),
+        })
+    }
+
+    // Resolves on the next tap (or double tap) after this is called
+    pub async fn wait_for_tap(&self) -> Tap {
+        self.tap.set(None);
+        NextTap { monitor: self }.await
+    }
+
+    pub fn get_tap_driver(&'static self) -> &'static mut dyn Future<Output = !> {
+        let future = async move || {
+            let mut sequence = 0;
+            loop {
+                if self.motor_controller.is_driving() || !self.imu.is_configured() {
+                    // Start from scratch once things settle down
+                    *self.detector.borrow_mut() = TapDetector::new();
+                    Waiter::new(IDLE_DELAY_MS).await;
+                    continue;
+                }
+
+                let sample = self.imu.next_accelerometer(sequence).await;
+                sequence = sample.sequence;
+
+                let config = TapConfig::from_params();
+                let tap = self
+                    .detector
+                    .borrow_mut()
+                    .update(sample.timestamp_ms, sample.value, &config);
+                if let Some(tap) = tap {
+                    self.tap.set(Some(tap));
+                    if let Some(waker) = self.waker.borrow_mut().take() {
+                        waker.wake();
+                    }
+                }
+            }
+        };
+        Allocator::get().new(future())
+    }
+}
+
+struct NextTap<'a> {
+    monitor: &'a TapMonitor,
+}
+
+impl<'a> Future for NextTap<'a> {
+    type Output = Tap;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        if let Some(tap) = self.monitor.tap.take() {
+            return Poll::Ready(tap);
+        }
+        *self.monitor.waker.borrow_mut() = Some(ctx.waker().clone());
+        Poll::Pending
+    }
+}
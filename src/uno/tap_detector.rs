@@ -0,0 +1,161 @@
+// The part of tap detection that doesn't touch the hardware, kept apart from the TapMonitor driver
+// so that it can be fed recorded traces on the host.  See tap.rs for what counts as a tap.
+use crate::math::{
+    self,
+    Vector3,
+};
+
+const TAP_MAX_DURATION_MS: u32 = 60;
+const BASELINE_ALPHA: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tap {
+    Single,
+    Double,
+}
+
+pub struct TapConfig {
+    pub threshold_g: f32,
+    pub double_tap_window_ms: u32,
+}
+
+pub struct TapDetector {
+    baseline: Option<Vector3>,
+    spike_start_ms: Option<u32>,
+    first_tap_ms: Option<u32>,
+}
+
+impl TapDetector {
+    pub fn new() -> TapDetector {
+        TapDetector {
+            baseline: None,
+            spike_start_ms: None,
+            first_tap_ms: None,
+        }
+    }
+
+    // `accel` is in g.  A single tap isn't reported until the double-tap window has passed.
+    pub fn update(&mut self, timestamp_ms: u32, accel: Vector3, config: &TapConfig) -> Option<Tap> {
+        let baseline = *self.baseline.get_or_insert(accel);
+        if math::norm(math::sub(accel, baseline)) > config.threshold_g {
+            if self.spike_start_ms.is_none() {
+                self.spike_start_ms = Some(timestamp_ms);
+            }
+            return None;
+        }
+        self.baseline = Some(math::add(
+            baseline,
+            math::scale(math::sub(accel, baseline), BASELINE_ALPHA),
+        ));
+
+        if let Some(start_ms) = self.spike_start_ms.take() {
+            if timestamp_ms.wrapping_sub(start_ms) <= TAP_MAX_DURATION_MS {
+                if self.first_tap_ms.take().is_some() {
+                    return Some(Tap::Double);
+                }
+                self.first_tap_ms = Some(start_ms);
+                return None;
+            }
+        }
+
+        match self.first_tap_ms {
+            Some(first_ms) if timestamp_ms.wrapping_sub(first_ms) > config.double_tap_window_ms => {
+                self.first_tap_ms = None;
+                Some(Tap::Single)
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_MS: u32 = 20;
+
+    // The parameter defaults
+    const CONFIG: TapConfig = TapConfig {
+        threshold_g: 0.4,
+        double_tap_window_ms: 400,
+    };
+
+    // Feeds a trace of x readings, one every SAMPLE_MS, with gravity on z, and returns every tap
+    // along with the index of the sample that reported it
+    fn run(trace: &[f32]) -> Vec<(usize, Tap)> {
+        let mut detector = TapDetector::new();
+        let mut taps = Vec::new();
+        for (i, &x) in trace.iter().enumerate() {
+            if let Some(tap) = detector.update(1000 + i as u32 * SAMPLE_MS, (x, 0.0, 1.0), &CONFIG) {
+                taps.push((i, tap));
+            }
+        }
+        taps
+    }
+
+    fn calm(count: usize) -> Vec<f32> {
+        vec![0.0; count]
+    }
+
+    // `samples` readings well past the threshold
+    fn spike(samples: usize) -> Vec<f32> {
+        (0..samples).map(|i| if i % 2 == 0 { 1.2 } else { -0.9 }).collect()
+    }
+
+    #[test]
+    fn single_tap_waits_out_the_double_tap_window() {
+        let mut trace = calm(10);
+        trace.extend(spike(2));
+        trace.extend(calm(30));
+
+        // The spike starts at sample 10, and the window runs for 400ms (20 samples) from there
+        assert_eq!(run(&trace), vec![(31, Tap::Single)]);
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut trace = calm(10);
+        trace.extend(spike(2));
+        trace.extend(calm(8));
+        trace.extend(spike(3)); // as long as a tap can be
+        trace.extend(calm(30));
+
+        assert_eq!(run(&trace), vec![(23, Tap::Double)]);
+    }
+
+    #[test]
+    fn second_tap_after_the_window_is_two_single_taps() {
+        let mut trace = calm(10);
+        trace.extend(spike(1));
+        trace.extend(calm(25));
+        trace.extend(spike(1));
+        trace.extend(calm(30));
+
+        assert_eq!(run(&trace), vec![(31, Tap::Single), (57, Tap::Single)]);
+    }
+
+    #[test]
+    fn long_disturbance_is_not_a_tap() {
+        // Picking the robot up: the reading stays away from the baseline for 80ms
+        let mut trace = calm(10);
+        trace.extend(spike(4));
+        trace.extend(calm(30));
+        assert!(run(&trace).is_empty());
+
+        // ...and it doesn't count as the first half of a double tap either
+        let mut trace = calm(10);
+        trace.extend(spike(4));
+        trace.extend(calm(5));
+        trace.extend(spike(1));
+        trace.extend(calm(30));
+        assert_eq!(run(&trace), vec![(40, Tap::Single)]);
+    }
+
+    #[test]
+    fn small_wobbles_are_ignored() {
+        // Vibration from something else on the table
+        let mut trace = calm(10);
+        trace.extend((0..100).map(|i| if i % 3 == 0 { 0.25 } else { -0.1 }));
+        assert!(run(&trace).is_empty());
+    }
+}
@@ -52,6 +52,12 @@ pub fn micros_no_interrupt() -> u32 {
     }
 }
 
+// Busy-wait; only for the handful of places that need a delay shorter than a millisecond
+pub fn delay_us(us: u32) {
+    let start = micros();
+    while micros().wrapping_sub(start) < us {}
+}
+
 // This will overflow after about 49 days
 pub fn millis() -> u32 {
     critical_section(|_| unsafe { ELAPSED_MS })
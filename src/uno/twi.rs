@@ -0,0 +1,415 @@
+// An interrupt-driven I2C (TWI) master.  Each step of a transaction (START, address, data byte,
+// ...) is kicked off by clearing TWINT, and the task then sleeps until the TWI interrupt fires, so
+// other drivers get to run while the bytes are on the wire.  The bus is shared by all of the
+// devices on it; a transaction holds the bus lock from START to STOP so they can't interleave.
+use crate::{
+    avr_async::NTASKS,
+    mem::Allocator,
+    uno::timers::{
+        self,
+        register_timed_waker,
+    },
+    util::*,
+};
+use arduino_uno::{
+    hal::port::{
+        mode::*,
+        portc::*,
+    },
+    pac::TWI as TwiRegs,
+};
+use avr_hal_generic::{
+    avr_device,
+    avr_device::interrupt::free as critical_section,
+};
+use core::{
+    cell::{
+        Cell,
+        RefCell,
+    },
+    future::Future,
+    pin::Pin,
+    ptr::{
+        read_volatile,
+        write_volatile,
+    },
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+};
+
+const CPU_FREQUENCY_HZ: u32 = 16_000_000;
+
+// Generous upper bound on a whole transaction; at 400kHz, a 6-byte read (with the register address
+// and both slave addresses) takes about 250us, but the millisecond timer only has 1ms resolution
+// and other drivers can hold up the one waiting on the bus for a few milliseconds
+const TRANSACTION_TIMEOUT_MS: u32 = 10;
+const TRANSACTION_ATTEMPTS: u8 = 3;
+
+// Bus recovery bit-bangs the lines through PORTC
+const SDA_BIT: u8 = 1 << 4;
+const SCL_BIT: u8 = 1 << 5;
+const RECOVERY_CLOCKS: u8 = 9; // enough to finish any byte a device might be halfway through
+const RECOVERY_HALF_PERIOD_US: u32 = 20;
+
+// TWCR bits
+const TWINT: u8 = 0x80;
+const TWEA: u8 = 0x40;
+const TWSTA: u8 = 0x20;
+const TWSTO: u8 = 0x10;
+const TWEN: u8 = 0x04;
+const TWIE: u8 = 0x01;
+
+// Status codes (TWSR with the prescaler bits masked off)
+const STATUS_START: u8 = 0x08;
+const STATUS_REP_START: u8 = 0x10;
+const STATUS_MT_SLA_ACK: u8 = 0x18;
+const STATUS_MT_SLA_NACK: u8 = 0x20;
+const STATUS_MT_DATA_ACK: u8 = 0x28;
+const STATUS_MT_DATA_NACK: u8 = 0x30;
+const STATUS_ARB_LOST: u8 = 0x38;
+const STATUS_MR_SLA_ACK: u8 = 0x40;
+const STATUS_MR_SLA_NACK: u8 = 0x48;
+const STATUS_MR_DATA_ACK: u8 = 0x50;
+const STATUS_MR_DATA_NACK: u8 = 0x58;
+
+static mut TWI_WAKER: Option<Waker> = None;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TwiError {
+    Timeout,
+    AddressNack,
+    DataNack,
+    ArbitrationLost,
+    BusError,
+}
+
+pub struct Twi {
+    regs: TwiRegs,
+    // We own the pins so nobody else can touch them, but bus recovery drives them through the port
+    // registers directly
+    _sda: PC4<Input<PullUp>>,
+    _scl: PC5<Input<PullUp>>,
+    busy: Cell<bool>,
+    lock_waiters: RefCell<[Option<Waker>; NTASKS]>,
+}
+
+impl Twi {
+    // Like the MotorController, this is a static reference because every device on the bus holds
+    // onto it
+    pub fn new(regs: TwiRegs, sda: PC4<Input<PullUp>>, scl: PC5<Input<PullUp>>, speed_hz: u32) -> &'static Twi {
+        // SCL = CPU / (16 + 2 * TWBR * prescaler); slow speeds need the prescaler to keep TWBR
+        // within a byte
+        let divider = (CPU_FREQUENCY_HZ / speed_hz - 16) / 2;
+        let (prescaler_bits, twbr) = if divider <= 0xff {
+            (0b00, divider)
+        } else if divider / 4 <= 0xff {
+            (0b01, divider / 4)
+        } else {
+            (0b10, divider / 16)
+        };
+        regs.twsr.write(|w| unsafe { w.bits(prescaler_bits) });
+        regs.twbr.write(|w| unsafe { w.bits(twbr as u8) });
+        regs.twcr.write(|w| unsafe { w.bits(TWEN) });
+
+        Allocator::get().new(Twi {
+            regs,
+            _sda: sda,
+            _scl: scl,
+            busy: Cell::new(false),
+            lock_waiters: RefCell::new(Default::default()),
+        })
+    }
+
+    pub async fn write(&self, addr: u8, bytes: &[u8]) -> Result<(), TwiError> {
+        self.transaction(addr, bytes, &mut []).await
+    }
+
+    pub async fn read(&self, addr: u8, buffer: &mut [u8]) -> Result<(), TwiError> {
+        self.transaction(addr, &[], buffer).await
+    }
+
+    // Write `bytes` (usually a register address) and then read into `buffer` after a repeated
+    // START, without giving up the bus in between
+    pub async fn write_read(&self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), TwiError> {
+        self.transaction(addr, bytes, buffer).await
+    }
+
+    // Failed transactions are retried a couple of times (recovering the bus first if it looks
+    // stuck) before the error is handed back to the caller
+    async fn transaction(&self, addr: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), TwiError> {
+        let mut guard = BusLock { twi: self }.await;
+
+        let mut result = Err(TwiError::Timeout);
+        for _ in 0..TRANSACTION_ATTEMPTS {
+            let mut deadline = Deadline {
+                ms: timers::millis() + TRANSACTION_TIMEOUT_MS,
+                armed: false,
+            };
+            result = self.transfer(addr, bytes, &mut *buffer, &mut deadline).await;
+            match result {
+                Ok(()) => {
+                    self.stop();
+                    break;
+                },
+                // Nobody answered at that address, so there's no point in asking again right away
+                Err(TwiError::AddressNack) => {
+                    self.stop();
+                    break;
+                },
+                Err(TwiError::DataNack) => self.stop(),
+                // We might not be driving the bus anymore, or a device might be holding SDA low
+                // partway through a byte
+                Err(_) => self.recover(),
+            }
+        }
+
+        guard.finished = true;
+        result
+    }
+
+    async fn transfer(
+        &self,
+        addr: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+        deadline: &mut Deadline,
+    ) -> Result<(), TwiError> {
+        // A zero-length write is still useful for probing whether a device is there
+        if !bytes.is_empty() || buffer.is_empty() {
+            self.start(deadline).await?;
+            self.send(addr << 1, STATUS_MT_SLA_ACK, STATUS_MT_SLA_NACK, deadline)
+                .await
+                .map_err(|e| {
+                    if e == TwiError::DataNack {
+                        TwiError::AddressNack
+                    } else {
+                        e
+                    }
+                })?;
+            for &byte in bytes {
+                self.send(byte, STATUS_MT_DATA_ACK, STATUS_MT_DATA_NACK, deadline)
+                    .await?;
+            }
+        }
+
+        if !buffer.is_empty() {
+            self.start(deadline).await?;
+            self.send(addr << 1 | 1, STATUS_MR_SLA_ACK, STATUS_MR_SLA_NACK, deadline)
+                .await
+                .map_err(|e| {
+                    if e == TwiError::DataNack {
+                        TwiError::AddressNack
+                    } else {
+                        e
+                    }
+                })?;
+
+            // ACK every byte but the last one, which tells the device we're done
+            let len = buffer.len();
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                let (ack, expected) = if i + 1 < len {
+                    (TWEA, STATUS_MR_DATA_ACK)
+                } else {
+                    (0, STATUS_MR_DATA_NACK)
+                };
+                self.kick(ack);
+                check(self.next_event(deadline).await?, expected, None)?;
+                *byte = self.regs.twdr.read().bits();
+            }
+        }
+        Ok(())
+    }
+
+    async fn start(&self, deadline: &mut Deadline) -> Result<(), TwiError> {
+        self.kick(TWSTA);
+        let status = self.next_event(deadline).await?;
+        if status == STATUS_REP_START {
+            return Ok(());
+        }
+        check(status, STATUS_START, None)
+    }
+
+    // A NACK is reported as DataNack; callers sending an address translate it
+    async fn send(&self, byte: u8, ack: u8, nack: u8, deadline: &mut Deadline) -> Result<(), TwiError> {
+        self.regs.twdr.write(|w| unsafe { w.bits(byte) });
+        self.kick(0);
+        check(self.next_event(deadline).await?, ack, Some(nack))
+    }
+
+    // Clearing TWINT (by writing a one to it) starts the next bus operation
+    fn kick(&self, extra_bits: u8) {
+        self.regs
+            .twcr
+            .write(|w| unsafe { w.bits(TWINT | TWEN | TWIE | extra_bits) });
+    }
+
+    fn next_event<'a>(&'a self, deadline: &'a mut Deadline) -> TwiEvent<'a> {
+        TwiEvent {
+            regs: &self.regs,
+            deadline,
+        }
+    }
+
+    fn stop(&self) {
+        self.regs.twcr.write(|w| unsafe { w.bits(TWINT | TWEN | TWSTO) });
+        // The STOP condition only takes a few microseconds and doesn't set TWINT when it's done
+        while self.regs.twcr.read().bits() & TWSTO != 0 {}
+    }
+
+    // A device that was interrupted partway through sending a byte (e.g., by a reset on our end or
+    // a glitch on the cable) will hold SDA low until it's clocked out the rest of the byte.  We
+    // take the lines away from the TWI module, pulse SCL until SDA is released, send a STOP by hand,
+    // and then start the module back up.
+    fn recover(&self) {
+        // Disabling the TWI module hands the pins back to PORTC (as inputs with pull-ups)
+        self.regs.twcr.write(|w| unsafe { w.bits(0) });
+        unsafe {
+            for _ in 0..RECOVERY_CLOCKS {
+                if read_volatile(PINC) & SDA_BIT != 0 {
+                    break;
+                }
+                drive_low(SCL_BIT);
+                timers::delay_us(RECOVERY_HALF_PERIOD_US);
+                release(SCL_BIT);
+                timers::delay_us(RECOVERY_HALF_PERIOD_US);
+            }
+
+            // STOP is SDA going high while SCL is high
+            drive_low(SDA_BIT);
+            timers::delay_us(RECOVERY_HALF_PERIOD_US);
+            release(SDA_BIT);
+            timers::delay_us(RECOVERY_HALF_PERIOD_US);
+        }
+        self.regs.twcr.write(|w| unsafe { w.bits(TWEN) });
+    }
+
+    fn unlock(&self) {
+        self.busy.set(false);
+        for slot in self.lock_waiters.borrow_mut().iter_mut() {
+            if let Some(waker) = slot.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+// The lines are open-drain: we either pull them low or let the pull-ups take them high
+unsafe fn drive_low(bit: u8) {
+    write_volatile(PORTC, read_volatile(PORTC) & !bit);
+    write_volatile(DDRC, read_volatile(DDRC) | bit);
+}
+
+unsafe fn release(bit: u8) {
+    write_volatile(DDRC, read_volatile(DDRC) & !bit);
+    write_volatile(PORTC, read_volatile(PORTC) | bit);
+}
+
+fn check(status: u8, expected: u8, nack: Option<u8>) -> Result<(), TwiError> {
+    match status {
+        s if s == expected => Ok(()),
+        s if Some(s) == nack => Err(TwiError::DataNack),
+        STATUS_ARB_LOST => Err(TwiError::ArbitrationLost),
+        _ => Err(TwiError::BusError), // includes the actual bus error status, 0x00
+    }
+}
+
+struct Deadline {
+    ms: u32,
+    armed: bool, // we only register one timed waker per transaction
+}
+
+// Resolves once the bus is free, and claims it until the guard is dropped
+struct BusLock<'a> {
+    twi: &'a Twi,
+}
+
+impl<'a> Future for BusLock<'a> {
+    type Output = BusGuard<'a>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        if !self.twi.busy.get() {
+            self.twi.busy.set(true);
+            return Poll::Ready(BusGuard {
+                twi: self.twi,
+                finished: false,
+            });
+        }
+
+        // A task that's polled again while it waits (or that waits on the bus from more than one
+        // place at once, e.g. under a select) keeps its one slot, so with a slot per task there's
+        // always room.  If there somehow isn't, we'd rather spin than sleep forever.
+        let mut waiters = self.twi.lock_waiters.borrow_mut();
+        let waker = ctx.waker();
+        let existing = waiters
+            .iter()
+            .position(|slot| matches!(slot, Some(w) if w.will_wake(waker)));
+        match existing.or_else(|| waiters.iter().position(|slot| slot.is_none())) {
+            Some(i) => waiters[i] = Some(waker.clone()),
+            None => waker.clone().wake(),
+        }
+        Poll::Pending
+    }
+}
+
+// If the transaction holding the bus gets dropped partway through (e.g., a state preempted by the
+// orientation monitor), a device may be stuck in the middle of a byte and the TWI module may still
+// have its interrupt enabled, so we put the bus back in order before handing it to anyone else
+struct BusGuard<'a> {
+    twi: &'a Twi,
+    finished: bool, // the transaction already left the bus idle
+}
+
+impl<'a> Drop for BusGuard<'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.twi.recover();
+        }
+        self.twi.unlock();
+    }
+}
+
+// Resolves with the status code once the current bus operation finishes
+struct TwiEvent<'a> {
+    regs: &'a TwiRegs,
+    deadline: &'a mut Deadline,
+}
+
+impl<'a> Future for TwiEvent<'a> {
+    type Output = Result<u8, TwiError>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let done = critical_section(|_| {
+            if self.regs.twcr.read().bits() & TWINT != 0 {
+                return true;
+            }
+            // Checking TWINT and storing the waker have to happen together, or the interrupt
+            // could sneak in between and we'd never get woken up
+            unsafe { TWI_WAKER = Some(ctx.waker().clone()) };
+            false
+        });
+        if done {
+            return Poll::Ready(Ok(self.regs.twsr.read().bits() & 0xf8));
+        }
+
+        if timers::millis() >= self.deadline.ms {
+            return Poll::Ready(Err(TwiError::Timeout));
+        } else if !self.deadline.armed {
+            register_timed_waker(self.deadline.ms, ctx.waker().clone());
+            self.deadline.armed = true;
+        }
+        Poll::Pending
+    }
+}
+
+#[avr_device::interrupt(atmega328p)]
+unsafe fn TWI() {
+    // TWINT stays set until we start the next operation, so turn off the interrupt or it will keep
+    // firing forever; writing a zero to TWINT leaves it alone
+    write_volatile(TWCR, read_volatile(TWCR) & !(TWIE | TWINT));
+    if let Some(waker) = TWI_WAKER.take() {
+        waker.wake();
+    }
+}
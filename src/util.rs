@@ -1,6 +1,3 @@
-use core::mem::MaybeUninit;
-use embedded_hal::digital::v2::InputPin;
-
 // These constants are useful for when we don't have access to the "uno" object but we still
 // need to do register accesses.  You may need to wrap these in read/write_volatile commands to
 // prevent various compiler optimizations.
@@ -15,13 +12,17 @@ pub const PCMSK2: *mut u8 = 0x6d as *mut u8;
 pub const TCNT0: *const u8 = 0x46 as *const u8;
 pub const TIFR0: *const u8 = 0x35 as *const u8;
 pub const OCR0A: *mut u8 = 0x47 as *mut u8;
+pub const EECR: *mut u8 = 0x3f as *mut u8;
+pub const PINB: *const u8 = 0x23 as *const u8;
+pub const PINC: *const u8 = 0x26 as *const u8;
+pub const DDRC: *mut u8 = 0x27 as *mut u8;
+pub const PORTC: *mut u8 = 0x28 as *mut u8;
+pub const PIND: *const u8 = 0x29 as *const u8;
+pub const TWCR: *mut u8 = 0xbc as *mut u8;
+pub const ADCL: *const u8 = 0x78 as *const u8;
+pub const ADCH: *const u8 = 0x79 as *const u8;
+pub const ADCSRA: *mut u8 = 0x7a as *mut u8;
+pub const ADMUX: *mut u8 = 0x7c as *mut u8;
+pub const DIDR0: *mut u8 = 0x7e as *mut u8;
 
-pub fn get_pin<T: InputPin>() -> T {
-    unsafe { MaybeUninit::uninit().assume_init() }
-}
-
-pub fn toggle_pc_interrupts() {
-    unsafe {
-        *PCICR ^= 0x07;
-    }
-}
+pub const EERIE: u8 = 0x08; // EEPROM ready interrupt enable bit in EECR